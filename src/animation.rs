@@ -0,0 +1,475 @@
+use crate::metadata::{self, ImageMetadata};
+use crate::overlay::{DecodeLimits, OverlayError, encode_image};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+use image::error::{
+    EncodingError, ImageFormatHint, LimitError, LimitErrorKind, ParameterError, ParameterErrorKind,
+};
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, Delay, DynamicImage, Frame, ImageDecoder, ImageError, ImageFormat, RgbaImage,
+};
+use palette::Srgb;
+use rayon::prelude::*;
+use std::io::Cursor;
+use std::time::Duration;
+
+/// How many times an animation is played, Times is at least 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopCount {
+    Forever,
+    Times(u16),
+}
+
+/// A frame of an animation covering the whole canvas and how long it is shown
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay: Duration,
+}
+
+/// The frames of an animated GIF or WebP
+#[derive(Clone, Debug, PartialEq)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    pub loop_count: LoopCount,
+}
+
+/// Decode all frames of an animated GIF or WebP, None for still images and other formats.
+/// The canvas from the header has to fit the limits before any frame is decoded and the decoded
+/// frames together have to stay within the max_alloc of the limits. Like still images every frame
+/// is converted to sRGB by the icc profile and oriented by the exif of the file
+pub fn decode_animation(
+    buffer: &[u8],
+    limits: &DecodeLimits,
+) -> Result<Option<Animation>, OverlayError> {
+    let decode_error = |e: ImageError| match e {
+        ImageError::Limits(_) => OverlayError::ImageTooLarge(e),
+        e => OverlayError::Decode(e),
+    };
+    let (frames, loop_count, icc, orientation) = match image::guess_format(buffer) {
+        Ok(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(Cursor::new(buffer)).map_err(decode_error)?;
+            check_canvas(&mut decoder, limits)?;
+            let (icc, orientation) = color_and_orientation(&mut decoder);
+            (
+                decoder.into_frames(),
+                gif_loop_count(buffer),
+                icc,
+                orientation,
+            )
+        }
+        Ok(ImageFormat::WebP) => {
+            let Ok(inner) = image_webp::WebPDecoder::new(Cursor::new(buffer)) else {
+                return Ok(None);
+            };
+            if !inner.is_animated() {
+                return Ok(None);
+            }
+            let loop_count = match inner.loop_count() {
+                image_webp::LoopCount::Forever => LoopCount::Forever,
+                image_webp::LoopCount::Times(n) => LoopCount::Times(n.get()),
+            };
+            let mut decoder = WebPDecoder::new(Cursor::new(buffer)).map_err(decode_error)?;
+            check_canvas(&mut decoder, limits)?;
+            let (icc, orientation) = color_and_orientation(&mut decoder);
+            (decoder.into_frames(), loop_count, icc, orientation)
+        }
+        _ => return Ok(None),
+    };
+
+    let mut decoded = Vec::new();
+    let mut bytes = 0u64;
+    for frame in frames {
+        let frame = frame.map_err(decode_error)?;
+        let (width, height) = frame.buffer().dimensions();
+        if width > limits.max_width || height > limits.max_height {
+            return Err(OverlayError::ImageTooLarge(ImageError::Limits(
+                LimitError::from_kind(LimitErrorKind::DimensionError),
+            )));
+        }
+        bytes += width as u64 * height as u64 * 4;
+        if bytes > limits.max_alloc {
+            return Err(OverlayError::ImageTooLarge(ImageError::Limits(
+                LimitError::from_kind(LimitErrorKind::InsufficientMemory),
+            )));
+        }
+        let delay = frame.delay().into();
+        let mut image = DynamicImage::ImageRgba8(frame.into_buffer());
+        if let Some(icc) = &icc {
+            image = metadata::convert_to_srgb(image, icc);
+        }
+        image.apply_orientation(orientation);
+        decoded.push(AnimationFrame {
+            delay,
+            image: image.into_rgba8(),
+        });
+    }
+    // a gif with a single frame is a still image
+    if decoded.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(Animation {
+        frames: decoded,
+        loop_count,
+    }))
+}
+
+/// Check the canvas size from the header against the limits and hand the limits to the decoder,
+/// the frame iterators allocate a full canvas before the first frame is returned
+fn check_canvas(
+    decoder: &mut impl ImageDecoder,
+    limits: &DecodeLimits,
+) -> Result<(), OverlayError> {
+    let (width, height) = decoder.dimensions();
+    if width > limits.max_width || height > limits.max_height {
+        return Err(OverlayError::ImageTooLarge(ImageError::Limits(
+            LimitError::from_kind(LimitErrorKind::DimensionError),
+        )));
+    }
+    if width as u64 * height as u64 * 4 > limits.max_alloc {
+        return Err(OverlayError::ImageTooLarge(ImageError::Limits(
+            LimitError::from_kind(LimitErrorKind::InsufficientMemory),
+        )));
+    }
+    decoder
+        .set_limits(limits.image_limits())
+        .map_err(OverlayError::ImageTooLarge)
+}
+
+/// The icc profile and the exif orientation of the file, broken metadata is ignored like for
+/// still images
+fn color_and_orientation(decoder: &mut impl ImageDecoder) -> (Option<Vec<u8>>, Orientation) {
+    let icc = decoder.icc_profile().unwrap_or(None);
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    (icc, orientation)
+}
+
+/// The loop count from the netscape extension, which comes before the first frame.
+/// Without it the animation is played once
+fn gif_loop_count(buffer: &[u8]) -> LoopCount {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let Ok(mut decoder) = options.read_info(Cursor::new(buffer)) else {
+        return LoopCount::Times(1);
+    };
+    let _ = decoder.next_frame_info();
+    match decoder.repeat() {
+        gif::Repeat::Infinite => LoopCount::Forever,
+        gif::Repeat::Finite(n) => LoopCount::Times(n.saturating_add(1)),
+    }
+}
+
+/// Encode the frames as an animated GIF or WebP keeping the delays and the loop count,
+/// WebP frames are encoded lossless like still WebP images
+pub fn encode_animation(
+    animation: &Animation,
+    format: ImageFormat,
+) -> Result<Vec<u8>, OverlayError> {
+    match format {
+        ImageFormat::Gif => encode_gif(animation),
+        ImageFormat::WebP => encode_webp(animation),
+        _ => Err(OverlayError::Encode(ImageError::Parameter(
+            ParameterError::from_kind(ParameterErrorKind::Generic(format!(
+                "{:?} does not support animation",
+                format
+            ))),
+        ))),
+    }
+}
+
+fn encode_gif(animation: &Animation) -> Result<Vec<u8>, OverlayError> {
+    let mut buf = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buf, 10);
+        let repeat = match animation.loop_count {
+            LoopCount::Forever => Repeat::Infinite,
+            LoopCount::Times(n) => Repeat::Finite(n.saturating_sub(1)),
+        };
+        encoder.set_repeat(repeat).map_err(OverlayError::Encode)?;
+        encoder
+            .encode_frames(animation.frames.iter().map(|frame| {
+                Frame::from_parts(
+                    frame.image.clone(),
+                    0,
+                    0,
+                    Delay::from_saturating_duration(frame.delay),
+                )
+            }))
+            .map_err(OverlayError::Encode)?;
+    }
+    Ok(buf)
+}
+
+/// Mux the frames into an animated WebP, every frame is a lossless bitstream covering the canvas
+/// so no blending or disposal is needed
+fn encode_webp(animation: &Animation) -> Result<Vec<u8>, OverlayError> {
+    let Some(first) = animation.frames.first() else {
+        return Err(webp_error("animation has no frames"));
+    };
+    let (width, height) = first.image.dimensions();
+    let bitstreams = animation
+        .frames
+        .par_iter()
+        .map(|frame| {
+            let encoded = encode_image(
+                &frame.image,
+                ImageFormat::WebP,
+                100,
+                Srgb::new(0, 0, 0),
+                &ImageMetadata::default(),
+            )?;
+            find_chunk(&encoded, b"VP8L")
+                .map(|payload| payload.to_vec())
+                .ok_or_else(|| webp_error("encoded frame has no VP8L chunk"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut body = Vec::new();
+    let mut vp8x = vec![0x10 | 0x02, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    write_chunk(&mut body, b"VP8X", &vp8x);
+
+    let loop_count = match animation.loop_count {
+        LoopCount::Forever => 0,
+        LoopCount::Times(n) => n,
+    };
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+    write_chunk(&mut body, b"ANIM", &anim);
+
+    for (frame, bitstream) in animation.frames.iter().zip(bitstreams) {
+        let (frame_width, frame_height) = frame.image.dimensions();
+        let duration = frame.delay.as_millis().min(0xFF_FFFF) as u32;
+        let mut anmf = Vec::with_capacity(bitstream.len() + 24);
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(frame_width - 1));
+        anmf.extend_from_slice(&u24(frame_height - 1));
+        anmf.extend_from_slice(&u24(duration));
+        // do not blend with the previous frame, no disposal
+        anmf.push(0x02);
+        write_chunk(&mut anmf, b"VP8L", &bitstream);
+        write_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut buf = Vec::with_capacity(body.len() + 12);
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    buf.extend_from_slice(b"WEBP");
+    buf.extend_from_slice(&body);
+    Ok(buf)
+}
+
+fn webp_error(message: &str) -> OverlayError {
+    OverlayError::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::WebP),
+        message.to_string(),
+    )))
+}
+
+fn u24(v: u32) -> [u8; 3] {
+    let [a, b, c, _] = v.to_le_bytes();
+    [a, b, c]
+}
+
+/// Append a RIFF chunk, odd sized payloads are padded to an even length
+fn write_chunk(buf: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    buf.extend_from_slice(fourcc);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        buf.push(0);
+    }
+}
+
+/// The payload of the first chunk with the fourcc in a WebP file
+fn find_chunk<'a>(webp: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into().ok()?) as usize;
+        let payload = webp.get(offset + 8..offset + 8 + size)?;
+        if &webp[offset..offset + 4] == fourcc {
+            return Some(payload);
+        }
+        offset += 8 + size + size % 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn animation(loop_count: LoopCount) -> Animation {
+        let colors = [
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 255, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+        ];
+        Animation {
+            frames: colors
+                .iter()
+                .enumerate()
+                .map(|(i, color)| AnimationFrame {
+                    image: RgbaImage::from_pixel(6, 4, *color),
+                    delay: Duration::from_millis(100 * (i as u64 + 1)),
+                })
+                .collect(),
+            loop_count,
+        }
+    }
+
+    #[test]
+    fn test_webp_animation_round_trip() {
+        let source = animation(LoopCount::Times(3));
+        let encoded = encode_animation(&source, ImageFormat::WebP).unwrap();
+        let decoded = decode_animation(&encoded, &DecodeLimits::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(decoded.loop_count, LoopCount::Times(3));
+        assert_eq!(decoded, source);
+    }
+
+    /// Add an ICCP chunk after the VP8X chunk and an EXIF chunk at the end of a WebP file
+    fn with_webp_metadata(webp: &[u8], icc: &[u8], exif: &[u8]) -> Vec<u8> {
+        // the VP8X chunk is 18 bytes after the 12 byte RIFF header, its flags come first
+        let mut body = webp[12..30].to_vec();
+        body[8] |= 0x20 | 0x08;
+        write_chunk(&mut body, b"ICCP", icc);
+        body.extend_from_slice(&webp[30..]);
+        write_chunk(&mut body, b"EXIF", exif);
+        let mut buf = b"RIFF".to_vec();
+        buf.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        buf.extend_from_slice(b"WEBP");
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    #[test]
+    fn test_decode_animation_color_profile_and_orientation() {
+        let png = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/images/display-p3.png"
+        ))
+        .unwrap();
+        let icc = image::codecs::png::PngDecoder::new(Cursor::new(png))
+            .unwrap()
+            .icc_profile()
+            .unwrap()
+            .unwrap();
+        let mut source = animation(LoopCount::Forever);
+        for frame in &mut source.frames {
+            frame.image = RgbaImage::from_pixel(6, 4, Rgba([200, 100, 50, 255]));
+        }
+        let encoded = encode_animation(&source, ImageFormat::WebP).unwrap();
+        // orientation 6 is rotated 90 degrees clockwise
+        let webp = with_webp_metadata(&encoded, &icc, &metadata::exif_with_orientation(6));
+        let decoded = decode_animation(&webp, &DecodeLimits::default())
+            .unwrap()
+            .unwrap();
+
+        let expected = metadata::convert_to_srgb(
+            DynamicImage::ImageRgba8(source.frames[0].image.clone()),
+            &icc,
+        )
+        .into_rgba8();
+        assert_ne!(expected.get_pixel(0, 0), &Rgba([200, 100, 50, 255]));
+        assert_eq!(decoded.frames.len(), 3);
+        for frame in &decoded.frames {
+            assert_eq!(frame.image.dimensions(), (4, 6));
+            assert_eq!(frame.image.get_pixel(0, 0), expected.get_pixel(0, 0));
+        }
+    }
+
+    #[test]
+    fn test_gif_animation_round_trip() {
+        for loop_count in [LoopCount::Forever, LoopCount::Times(1), LoopCount::Times(4)] {
+            let source = animation(loop_count);
+            let encoded = encode_animation(&source, ImageFormat::Gif).unwrap();
+            let decoded = decode_animation(&encoded, &DecodeLimits::default())
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(decoded.loop_count, loop_count);
+            assert_eq!(decoded.frames.len(), 3);
+            for (decoded, source) in decoded.frames.iter().zip(&source.frames) {
+                assert_eq!(decoded.delay, source.delay);
+                let p = decoded.image.get_pixel(3, 2);
+                let s = source.image.get_pixel(3, 2);
+                assert!((0..3).all(|i| (p[i] as i32 - s[i] as i32).abs() < 8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_animation_still_images() {
+        let still = encode_image(
+            &RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255])),
+            ImageFormat::WebP,
+            80,
+            Srgb::new(0, 0, 0),
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert!(
+            decode_animation(&still, &DecodeLimits::default())
+                .unwrap()
+                .is_none()
+        );
+        let png = encode_image(
+            &RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255])),
+            ImageFormat::Png,
+            80,
+            Srgb::new(0, 0, 0),
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert!(
+            decode_animation(&png, &DecodeLimits::default())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_decode_animation_limits() {
+        let encoded = encode_animation(&animation(LoopCount::Forever), ImageFormat::WebP).unwrap();
+        // two frames fit but not all three
+        let limits = DecodeLimits {
+            max_alloc: 6 * 4 * 4 * 2,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode_animation(&encoded, &limits),
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_animation_canvas_limits() {
+        // a single 1x1 frame on a 65535x65535 canvas declared by the header
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0]);
+        gif.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0x80]);
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(&[2, 2, 0x44, 0x01, 0, 0x3b]);
+        assert!(matches!(
+            decode_animation(&gif, &DecodeLimits::default()),
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+        // within the dimensions the canvas still has to fit the allocation limit
+        let limits = DecodeLimits {
+            max_width: 65535,
+            max_height: 65535,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode_animation(&gif, &limits),
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+    }
+}
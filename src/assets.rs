@@ -0,0 +1,68 @@
+use image::RgbaImage;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The images of a directory by file name without extension, used as watermarks and logos.
+/// Files that are not images are skipped
+#[derive(Default)]
+pub struct AssetLibrary {
+    assets: HashMap<String, Arc<RgbaImage>>,
+}
+
+impl AssetLibrary {
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut assets = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            match image::open(&path) {
+                Ok(img) => {
+                    assets.insert(name.to_string(), Arc::new(img.into_rgba8()));
+                }
+                Err(e) => log::warn!("skipping asset {}: {}", path.display(), e),
+            }
+        }
+        Ok(Self { assets })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RgbaImage>> {
+        self.assets.get(name).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_asset_library_loads_images_by_name() {
+        let dir = std::env::temp_dir().join(format!("overlay-assets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        RgbaImage::from_pixel(4, 2, Rgba([255, 0, 0, 128]))
+            .save(dir.join("logo.png"))
+            .unwrap();
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let assets = AssetLibrary::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(assets.len(), 1);
+        let logo = assets.get("logo").unwrap();
+        assert_eq!(logo.dimensions(), (4, 2));
+        assert_eq!(*logo.get_pixel(0, 0), Rgba([255, 0, 0, 128]));
+        assert!(assets.get("notes").is_none());
+    }
+}
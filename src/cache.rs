@@ -0,0 +1,579 @@
+use crate::metrics;
+use crate::overlay::OverlayInfo;
+use actix_web::web::Bytes;
+use lru::LruCache;
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Disk cache namespace for the raw bytes fetched from upstream, keyed by url
+pub const SOURCES: &str = "sources";
+/// Disk cache namespace for encoded images, keyed by the normalized request
+pub const RENDERS: &str = "renders";
+
+/// An encoded image together with what is needed to answer a request with it
+#[derive(Clone, Debug)]
+pub struct CachedImage {
+    pub body: Bytes,
+    pub content_type: &'static str,
+    pub info: OverlayInfo,
+}
+
+/// Counters and current size of the cache, disk_hits are misses in memory found on disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+struct Entry {
+    image: CachedImage,
+    size: usize,
+    inserted: Instant,
+}
+
+struct Entries {
+    lru: LruCache<String, Entry>,
+    bytes: usize,
+}
+
+/// In memory LRU cache of rendered images bounded by the total size of the keys and bodies.
+/// Entries older than the ttl are treated as missing and dropped when looked up.
+/// With a disk cache attached, memory misses are looked up on disk and every insert is
+/// also written to disk
+pub struct RenderCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+    ttl: Duration,
+    disk: Option<Arc<DiskCache>>,
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RenderCache {
+    /// A cache holding at most max_bytes, a max_bytes of 0 disables the cache
+    pub fn new(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            max_bytes,
+            ttl,
+            disk: None,
+            hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_disk(mut self, disk: Arc<DiskCache>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedImage> {
+        if let Some(found) = self.get_memory(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            metrics::count_cache_lookup(metrics::Cache::Render, metrics::Lookup::Hit);
+            return Some(found);
+        }
+        if let Some(disk) = &self.disk
+            && let Some((data, written)) = disk.get_written(RENDERS, key).await
+            && let Some(found) = decode_render(&data)
+        {
+            self.disk_hits.fetch_add(1, Ordering::Relaxed);
+            metrics::count_cache_lookup(metrics::Cache::Render, metrics::Lookup::DiskHit);
+            // the entry keeps its age, so the ttl still counts from when it was rendered
+            let age = written.elapsed().unwrap_or_default();
+            let inserted = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+            self.insert_memory(key.to_string(), found.clone(), inserted);
+            return Some(found);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        metrics::count_cache_lookup(metrics::Cache::Render, metrics::Lookup::Miss);
+        None
+    }
+
+    /// Store the image in memory and on disk when a disk cache is attached
+    pub async fn insert(&self, key: String, image: CachedImage) {
+        if let Some(disk) = &self.disk {
+            disk.put(RENDERS, &key, encode_render(&image)).await;
+        }
+        self.insert_memory(key, image, Instant::now());
+    }
+
+    fn get_memory(&self, key: &str) -> Option<CachedImage> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.lru.get(key) {
+            Some(entry) if entry.inserted.elapsed() <= self.ttl => Some(entry.image.clone()),
+            Some(_) => {
+                if let Some(expired) = entries.lru.pop(key) {
+                    entries.bytes -= expired.size;
+                }
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store the image, evicting the least recently used entries until it fits.
+    /// Images larger than the whole cache are not stored
+    fn insert_memory(&self, key: String, image: CachedImage, inserted: Instant) {
+        let size = key.len() + image.body.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.lru.pop(&key) {
+            entries.bytes -= old.size;
+        }
+        while entries.bytes + size > self.max_bytes {
+            match entries.lru.pop_lru() {
+                Some((_, evicted)) => entries.bytes -= evicted.size,
+                None => break,
+            }
+        }
+        entries.bytes += size;
+        entries.lru.put(
+            key,
+            Entry {
+                image,
+                size,
+                inserted,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.lru.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
+
+/// Metadata stored in front of an encoded image on disk
+#[derive(Serialize, Deserialize)]
+struct RenderHeader {
+    content_type: String,
+    color: [u8; 3],
+    source_width: u32,
+    source_height: u32,
+    width: u32,
+    height: u32,
+}
+
+/// A rendered image on disk is the length of the json header as u32 little endian,
+/// the header and then the encoded image
+fn encode_render(image: &CachedImage) -> Vec<u8> {
+    let info = &image.info;
+    let header = serde_json::to_vec(&RenderHeader {
+        content_type: image.content_type.to_string(),
+        color: [info.color.red, info.color.green, info.color.blue],
+        source_width: info.source_width,
+        source_height: info.source_height,
+        width: info.width,
+        height: info.height,
+    })
+    .unwrap_or_default();
+    let mut data = Vec::with_capacity(4 + header.len() + image.body.len());
+    data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    data.extend_from_slice(&header);
+    data.extend_from_slice(&image.body);
+    data
+}
+
+fn decode_render(data: &[u8]) -> Option<CachedImage> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let header: RenderHeader = serde_json::from_slice(data.get(4..4 + len)?).ok()?;
+    let content_type = image::ImageFormat::from_mime_type(&header.content_type)?.to_mime_type();
+    let [r, g, b] = header.color;
+    Some(CachedImage {
+        body: Bytes::copy_from_slice(&data[4 + len..]),
+        content_type,
+        info: OverlayInfo {
+            color: Srgb::new(r, g, b),
+            source_width: header.source_width,
+            source_height: header.source_height,
+            width: header.width,
+            height: header.height,
+        },
+    })
+}
+
+/// Start of every disk cache file, files written without it are treated as missing
+const DISK_MAGIC: &[u8; 4] = b"ovc1";
+/// The magic followed by the write time in milliseconds since the epoch as u64 little endian
+const DISK_HEADER_LEN: usize = 12;
+/// Time to live of disk cache entries unless configured otherwise
+pub const DEFAULT_DISK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Persistent cache of byte blobs in a directory that can be shared between instances.
+/// Files are addressed by the sha256 of the key below a namespace directory and start with
+/// the time they were written, entries older than the ttl are removed when read. The
+/// modification time is updated on reads and the oldest files are removed when the total
+/// size grows beyond max_bytes
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    bytes: AtomicU64,
+    evicting: Mutex<()>,
+}
+
+impl DiskCache {
+    /// Open or create the cache directory and count the size of the files already in it
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let bytes = cache_files(&dir).iter().map(|f| f.size).sum();
+        Ok(Self {
+            dir,
+            max_bytes,
+            ttl: DEFAULT_DISK_TTL,
+            bytes: AtomicU64::new(bytes),
+            evicting: Mutex::new(()),
+        })
+    }
+
+    /// How long entries are used after they were written, a day by default
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub async fn get(self: &Arc<Self>, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        self.get_written(namespace, key).await.map(|(data, _)| data)
+    }
+
+    /// The data and the time it was written, expired entries are removed and missing
+    pub async fn get_written(
+        self: &Arc<Self>,
+        namespace: &str,
+        key: &str,
+    ) -> Option<(Vec<u8>, SystemTime)> {
+        let path = self.path(namespace, key);
+        let cache = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut data = fs::read(&path).ok()?;
+            let written = match read_written(&data) {
+                Some(written) if written.elapsed().unwrap_or_default() <= cache.ttl => written,
+                _ => {
+                    if fs::remove_file(&path).is_ok() {
+                        cache.bytes.fetch_sub(data.len() as u64, Ordering::Relaxed);
+                    }
+                    return None;
+                }
+            };
+            // mark as recently used, failing to do so only affects the eviction order
+            let _ = fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+            data.drain(..DISK_HEADER_LEN);
+            Some((data, written))
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Write the data, failures are logged since the cache is only an optimization
+    pub async fn put(self: &Arc<Self>, namespace: &str, key: &str, data: Vec<u8>) {
+        let path = self.path(namespace, key);
+        let cache = self.clone();
+        let written = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut file = Vec::with_capacity(DISK_HEADER_LEN + data.len());
+        file.extend_from_slice(DISK_MAGIC);
+        file.extend_from_slice(&written.to_le_bytes());
+        file.extend_from_slice(&data);
+        let data = file;
+        let result = tokio::task::spawn_blocking(move || {
+            write_atomic(&path, &data)?;
+            let total = cache.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            if total + data.len() as u64 > cache.max_bytes {
+                cache.evict();
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await;
+        if let Ok(Err(e)) = result {
+            log::warn!("failed to write disk cache entry: {}", e);
+        }
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> PathBuf {
+        let hash: String = Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join(namespace).join(&hash[..2]).join(hash)
+    }
+
+    /// Remove the least recently used files until the cache is below 90% of max_bytes
+    fn evict(&self) {
+        let Ok(_guard) = self.evicting.try_lock() else {
+            return;
+        };
+        let mut files = cache_files(&self.dir);
+        files.sort_by_key(|f| f.modified);
+        let mut total: u64 = files.iter().map(|f| f.size).sum();
+        let target = self.max_bytes / 10 * 9;
+        for file in files {
+            if total <= target {
+                break;
+            }
+            if fs::remove_file(&file.path).is_ok() {
+                total -= file.size;
+            }
+        }
+        self.bytes.store(total, Ordering::Relaxed);
+    }
+}
+
+/// The write time from the header of a disk cache file
+fn read_written(data: &[u8]) -> Option<SystemTime> {
+    if data.get(..4)? != DISK_MAGIC {
+        return None;
+    }
+    let millis = u64::from_le_bytes(data.get(4..DISK_HEADER_LEN)?.try_into().ok()?);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+struct CacheFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// All cache files below dir, temporary files of unfinished writes are skipped
+fn cache_files(dir: &Path) -> Vec<CacheFile> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(read_dir) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in read_dir.flatten() {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let path = entry.path();
+            if meta.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_none_or(|ext| ext != "tmp") {
+                files.push(CacheFile {
+                    path,
+                    size: meta.len(),
+                    modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+    files
+}
+
+/// Write to a temporary file and rename it so readers never see a partial file
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension(format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: usize) -> CachedImage {
+        CachedImage {
+            body: Bytes::from(vec![0u8; size]),
+            content_type: "image/png",
+            info: OverlayInfo {
+                color: Srgb::new(1, 2, 3),
+                source_width: 1,
+                source_height: 1,
+                width: 1,
+                height: 1,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_cache_hit_and_miss() {
+        let cache = RenderCache::new(1024, Duration::from_secs(60));
+        assert!(cache.get("a").await.is_none());
+        cache.insert("a".into(), image(10)).await;
+        let hit = cache.get("a").await.unwrap();
+        assert_eq!(hit.body.len(), 10);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                disk_hits: 0,
+                misses: 1,
+                entries: 1,
+                bytes: 11,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_cache_evicts_least_recently_used() {
+        let cache = RenderCache::new(100, Duration::from_secs(60));
+        cache.insert("a".into(), image(40)).await;
+        cache.insert("b".into(), image(40)).await;
+        // touch a so b is the least recently used
+        cache.get("a").await;
+        cache.insert("c".into(), image(40)).await;
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        assert_eq!(cache.stats().bytes, 82);
+
+        // larger than the whole cache, not stored and nothing evicted
+        cache.insert("d".into(), image(200)).await;
+        assert!(cache.get("d").await.is_none());
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_render_cache_expires_entries() {
+        let cache = RenderCache::new(1024, Duration::ZERO);
+        cache.insert("a".into(), image(10)).await;
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(cache.get("a").await.is_none());
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_render_cache_falls_back_to_disk() {
+        let dir = temp_dir("overlay-render-cache");
+        let disk = Arc::new(DiskCache::open(&dir, 1024 * 1024).unwrap());
+        RenderCache::new(1024, Duration::from_secs(60))
+            .with_disk(disk.clone())
+            .insert("a".into(), image(10))
+            .await;
+
+        // a new memory cache, as after a restart
+        let cache = RenderCache::new(1024, Duration::from_secs(60)).with_disk(disk);
+        let found = cache.get("a").await.unwrap();
+        assert_eq!(found.body.len(), 10);
+        assert_eq!(found.content_type, "image/png");
+        assert_eq!(found.info, image(10).info);
+        assert!(cache.get("a").await.is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.disk_hits, stats.misses), (1, 1, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_evicts_oldest_files() {
+        let dir = temp_dir("overlay-disk-cache");
+        let disk = Arc::new(DiskCache::open(&dir, 120).unwrap());
+        disk.put(SOURCES, "a", vec![1; 40]).await;
+        disk.put(SOURCES, "b", vec![2; 40]).await;
+        // make a older than b, then read it so it becomes the most recently used
+        fs::File::options()
+            .write(true)
+            .open(disk.path(SOURCES, "a"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(disk.get(SOURCES, "a").await, Some(vec![1; 40]));
+        disk.put(SOURCES, "c", vec![3; 40]).await;
+
+        assert!(disk.get(SOURCES, "a").await.is_some());
+        assert!(disk.get(SOURCES, "b").await.is_none());
+        assert!(disk.get(SOURCES, "c").await.is_some());
+        assert_eq!(disk.get(RENDERS, "a").await, None);
+
+        // the size of existing files is counted when opened again
+        let reopened = DiskCache::open(&dir, 120).unwrap();
+        assert_eq!(
+            reopened.bytes.load(Ordering::Relaxed),
+            2 * (40 + DISK_HEADER_LEN as u64)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_expires_entries() {
+        let dir = temp_dir("overlay-disk-cache-ttl");
+        let disk = Arc::new(
+            DiskCache::open(&dir, 1024 * 1024)
+                .unwrap()
+                .with_ttl(Duration::from_millis(50)),
+        );
+        disk.put(SOURCES, "a", vec![1; 10]).await;
+        assert_eq!(disk.get(SOURCES, "a").await, Some(vec![1; 10]));
+        std::thread::sleep(Duration::from_millis(60));
+
+        // expired entries are a miss and removed
+        assert_eq!(disk.get(SOURCES, "a").await, None);
+        assert!(!disk.path(SOURCES, "a").exists());
+        assert_eq!(disk.bytes.load(Ordering::Relaxed), 0);
+
+        // files without the header, like those of older versions, are a miss as well
+        write_atomic(&disk.path(SOURCES, "b"), &[1; 10]).unwrap();
+        assert_eq!(disk.get(SOURCES, "b").await, None);
+
+        // a render found on disk keeps its age in memory
+        let render_disk = Arc::new(
+            DiskCache::open(&dir, 1024 * 1024)
+                .unwrap()
+                .with_ttl(Duration::from_secs(60)),
+        );
+        RenderCache::new(1024, Duration::from_secs(60))
+            .with_disk(render_disk.clone())
+            .insert("r".into(), image(10))
+            .await;
+        std::thread::sleep(Duration::from_millis(60));
+        let cache = RenderCache::new(1024, Duration::from_millis(50)).with_disk(render_disk);
+        assert!(cache.get("r").await.is_some());
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.lru.peek("r").unwrap().inserted.elapsed() >= Duration::from_millis(60));
+        drop(entries);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+use crate::cache;
+use crate::overlay::{DecodeLimits, FetchLimits};
+use crate::policy::FetchPolicy;
+use crate::{Exponent, Fade, Midpoint, Strength, option_from_number_deserialize};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Environment variable with the path of the TOML configuration file
+pub const CONFIG_PATH_VAR: &str = "OVERLAY_CONFIG";
+
+/// The server configuration, read from the TOML file in OVERLAY_CONFIG with the OVERLAY_*
+/// environment variables applied on top. Everything left out keeps its default
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub fetch: FetchConfig,
+    pub decode: DecodeConfig,
+    pub cache: CacheConfig,
+    pub resources: ResourcesConfig,
+    pub gradient: GradientDefaults,
+}
+
+/// workers: number of worker threads, defaults to the number of cpu cores
+/// log_filter: env_logger filter used when RUST_LOG is not set
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub workers: Option<usize>,
+    pub log_filter: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            workers: None,
+            log_filter: "info".to_string(),
+        }
+    }
+}
+
+/// The fetch policy and limits for upstream images, see FetchPolicy and FetchLimits
+/// max_idle_connections: idle connections kept open per upstream host
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchConfig {
+    pub allow_hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+    pub allow_private: bool,
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    pub max_body_bytes: u64,
+    pub max_redirects: usize,
+    pub max_idle_connections: usize,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        let policy = FetchPolicy::default();
+        let limits = FetchLimits::default();
+        Self {
+            allow_hosts: policy.allow_hosts,
+            deny_hosts: policy.deny_hosts,
+            allow_private: policy.allow_private,
+            connect_timeout_secs: limits.connect_timeout.as_secs(),
+            timeout_secs: limits.timeout.as_secs(),
+            max_body_bytes: limits.max_body_bytes,
+            max_redirects: limits.max_redirects,
+            max_idle_connections: limits.max_idle_connections,
+        }
+    }
+}
+
+/// The decode limits, see DecodeLimits. A downscale_above of 0 turns downscaling off
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodeConfig {
+    pub max_image_width: u32,
+    pub max_image_height: u32,
+    pub max_decode_bytes: u64,
+    pub downscale_above: u32,
+}
+
+impl Default for DecodeConfig {
+    fn default() -> Self {
+        let limits = DecodeLimits::default();
+        Self {
+            max_image_width: limits.max_width,
+            max_image_height: limits.max_height,
+            max_decode_bytes: limits.max_alloc,
+            downscale_above: limits.downscale_above.unwrap_or(0),
+        }
+    }
+}
+
+/// max_bytes and ttl_secs size the in-memory render cache, a max_bytes of 0 disables it
+/// The disk cache is only used when disk_dir is set, its entries expire after disk_ttl_secs
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_bytes: usize,
+    pub ttl_secs: u64,
+    pub disk_dir: Option<PathBuf>,
+    pub disk_max_bytes: u64,
+    pub disk_ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 256 * 1024 * 1024,
+            ttl_secs: 600,
+            disk_dir: None,
+            disk_max_bytes: 1024 * 1024 * 1024,
+            disk_ttl_secs: cache::DEFAULT_DISK_TTL.as_secs(),
+        }
+    }
+}
+
+/// Directories with the fonts for text layers and the images for watermarks
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourcesConfig {
+    pub font_dir: Option<PathBuf>,
+    pub assets_dir: Option<PathBuf>,
+}
+
+/// Gradient values used for requests that leave them out, validated like the query parameters
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GradientDefaults {
+    #[serde(deserialize_with = "option_from_number_deserialize")]
+    pub(crate) fade: Option<Fade>,
+    #[serde(deserialize_with = "option_from_number_deserialize")]
+    pub(crate) midpoint: Option<Midpoint>,
+    #[serde(deserialize_with = "option_from_number_deserialize")]
+    pub(crate) exponent: Option<Exponent>,
+    #[serde(deserialize_with = "option_from_number_deserialize")]
+    pub(crate) top_strength: Option<Strength>,
+    #[serde(deserialize_with = "option_from_number_deserialize")]
+    pub(crate) bottom_strength: Option<Strength>,
+}
+
+/// Errors that stop the server from starting with the configuration
+/// Read: the configuration file could not be read
+/// Parse: the configuration file is not valid TOML or has invalid values
+/// Env: an environment variable has an invalid value
+/// Invalid: the values can be read but do not work together
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, io::Error),
+    Parse(String, toml::de::Error),
+    Env {
+        name: String,
+        value: String,
+        message: String,
+    },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read config {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path, e),
+            ConfigError::Env {
+                name,
+                value,
+                message,
+            } => write!(f, "invalid {}={}: {}", name, value, message),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the configuration of the process, the file is only read when OVERLAY_CONFIG is set
+    pub fn load() -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> = std::env::vars()
+            .filter(|(name, _)| name.starts_with("OVERLAY_"))
+            .collect();
+        let file = match vars.get(CONFIG_PATH_VAR) {
+            Some(path) => Some((
+                path.as_str(),
+                fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?,
+            )),
+            None => None,
+        };
+        Config::from_sources(file.as_ref().map(|(p, c)| (*p, c.as_str())), &vars)
+    }
+
+    /// Parse the file given as path and contents, apply the variables and validate the result
+    fn from_sources(
+        file: Option<(&str, &str)>,
+        vars: &HashMap<String, String>,
+    ) -> Result<Config, ConfigError> {
+        let mut config = match file {
+            Some((path, contents)) => {
+                toml::from_str(contents).map_err(|e| ConfigError::Parse(path.to_string(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(&Env(vars))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, env: &Env) -> Result<(), ConfigError> {
+        env.set("OVERLAY_HOST", &mut self.server.host)?;
+        env.set("OVERLAY_PORT", &mut self.server.port)?;
+        env.set_option("OVERLAY_WORKERS", &mut self.server.workers)?;
+        env.set("OVERLAY_LOG_FILTER", &mut self.server.log_filter)?;
+        env.set_list("OVERLAY_ALLOW_HOSTS", &mut self.fetch.allow_hosts);
+        env.set_list("OVERLAY_DENY_HOSTS", &mut self.fetch.deny_hosts);
+        env.set("OVERLAY_ALLOW_PRIVATE", &mut self.fetch.allow_private)?;
+        env.set(
+            "OVERLAY_CONNECT_TIMEOUT_SECS",
+            &mut self.fetch.connect_timeout_secs,
+        )?;
+        env.set("OVERLAY_TIMEOUT_SECS", &mut self.fetch.timeout_secs)?;
+        env.set("OVERLAY_MAX_BODY_BYTES", &mut self.fetch.max_body_bytes)?;
+        env.set("OVERLAY_MAX_REDIRECTS", &mut self.fetch.max_redirects)?;
+        env.set(
+            "OVERLAY_MAX_IDLE_CONNECTIONS",
+            &mut self.fetch.max_idle_connections,
+        )?;
+        env.set("OVERLAY_MAX_IMAGE_WIDTH", &mut self.decode.max_image_width)?;
+        env.set(
+            "OVERLAY_MAX_IMAGE_HEIGHT",
+            &mut self.decode.max_image_height,
+        )?;
+        env.set(
+            "OVERLAY_MAX_DECODE_BYTES",
+            &mut self.decode.max_decode_bytes,
+        )?;
+        env.set("OVERLAY_DOWNSCALE_ABOVE", &mut self.decode.downscale_above)?;
+        env.set("OVERLAY_CACHE_MAX_BYTES", &mut self.cache.max_bytes)?;
+        env.set("OVERLAY_CACHE_TTL_SECS", &mut self.cache.ttl_secs)?;
+        env.set_option("OVERLAY_DISK_CACHE_DIR", &mut self.cache.disk_dir)?;
+        env.set(
+            "OVERLAY_DISK_CACHE_MAX_BYTES",
+            &mut self.cache.disk_max_bytes,
+        )?;
+        env.set("OVERLAY_DISK_CACHE_TTL_SECS", &mut self.cache.disk_ttl_secs)?;
+        env.set_option("OVERLAY_FONT_DIR", &mut self.resources.font_dir)?;
+        env.set_option("OVERLAY_ASSETS_DIR", &mut self.resources.assets_dir)?;
+        env.set_option("OVERLAY_FADE", &mut self.gradient.fade)?;
+        env.set_option("OVERLAY_MIDPOINT", &mut self.gradient.midpoint)?;
+        env.set_option("OVERLAY_EXPONENT", &mut self.gradient.exponent)?;
+        env.set_option("OVERLAY_TOP_STRENGTH", &mut self.gradient.top_strength)?;
+        env.set_option(
+            "OVERLAY_BOTTOM_STRENGTH",
+            &mut self.gradient.bottom_strength,
+        )?;
+        Ok(())
+    }
+
+    /// Check the values that can be parsed but would fail or misbehave at runtime
+    fn validate(&self) -> Result<(), ConfigError> {
+        let checks = [
+            (
+                !self.server.host.is_empty(),
+                "server.host (OVERLAY_HOST) must not be empty",
+            ),
+            (
+                self.server.port > 0,
+                "server.port (OVERLAY_PORT) must be greater than 0",
+            ),
+            (
+                self.server.workers != Some(0),
+                "server.workers (OVERLAY_WORKERS) must be greater than 0",
+            ),
+            (
+                self.fetch.connect_timeout_secs > 0,
+                "fetch.connect_timeout_secs (OVERLAY_CONNECT_TIMEOUT_SECS) must be greater than 0",
+            ),
+            (
+                self.fetch.timeout_secs >= self.fetch.connect_timeout_secs,
+                "fetch.timeout_secs (OVERLAY_TIMEOUT_SECS) must not be less than fetch.connect_timeout_secs",
+            ),
+            (
+                self.fetch.max_body_bytes > 0,
+                "fetch.max_body_bytes (OVERLAY_MAX_BODY_BYTES) must be greater than 0",
+            ),
+            (
+                self.decode.max_image_width > 0,
+                "decode.max_image_width (OVERLAY_MAX_IMAGE_WIDTH) must be greater than 0",
+            ),
+            (
+                self.decode.max_image_height > 0,
+                "decode.max_image_height (OVERLAY_MAX_IMAGE_HEIGHT) must be greater than 0",
+            ),
+            (
+                self.decode.max_decode_bytes > 0,
+                "decode.max_decode_bytes (OVERLAY_MAX_DECODE_BYTES) must be greater than 0",
+            ),
+            (
+                self.cache.disk_dir.is_none() || self.cache.disk_max_bytes > 0,
+                "cache.disk_max_bytes (OVERLAY_DISK_CACHE_MAX_BYTES) must be greater than 0",
+            ),
+            (
+                self.cache.disk_dir.is_none() || self.cache.disk_ttl_secs > 0,
+                "cache.disk_ttl_secs (OVERLAY_DISK_CACHE_TTL_SECS) must be greater than 0",
+            ),
+        ];
+        match checks.iter().find(|(valid, _)| !valid) {
+            Some((_, message)) => Err(ConfigError::Invalid(message.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn fetch_policy(&self) -> FetchPolicy {
+        let hosts = |hosts: &[String]| -> Vec<String> {
+            hosts
+                .iter()
+                .map(|h| h.trim().to_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect()
+        };
+        FetchPolicy {
+            allow_hosts: hosts(&self.fetch.allow_hosts),
+            deny_hosts: hosts(&self.fetch.deny_hosts),
+            allow_private: self.fetch.allow_private,
+        }
+    }
+
+    pub fn fetch_limits(&self) -> FetchLimits {
+        FetchLimits {
+            connect_timeout: Duration::from_secs(self.fetch.connect_timeout_secs),
+            timeout: Duration::from_secs(self.fetch.timeout_secs),
+            max_body_bytes: self.fetch.max_body_bytes,
+            max_redirects: self.fetch.max_redirects,
+            max_idle_connections: self.fetch.max_idle_connections,
+        }
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_width: self.decode.max_image_width,
+            max_height: self.decode.max_image_height,
+            max_alloc: self.decode.max_decode_bytes,
+            downscale_above: Some(self.decode.downscale_above).filter(|&max| max > 0),
+        }
+    }
+}
+
+/// The OVERLAY_* environment variables, parsed with the FromStr implementation of the field
+struct Env<'a>(&'a HashMap<String, String>);
+
+impl Env<'_> {
+    fn parse<T>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let Some(value) = self.0.get(name) else {
+            return Ok(None);
+        };
+        value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| ConfigError::Env {
+                name: name.to_string(),
+                value: value.clone(),
+                message: e.to_string(),
+            })
+    }
+
+    fn set<T>(&self, name: &str, field: &mut T) -> Result<(), ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.parse(name)? {
+            *field = value;
+        }
+        Ok(())
+    }
+
+    fn set_option<T>(&self, name: &str, field: &mut Option<T>) -> Result<(), ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.parse(name)? {
+            *field = Some(value);
+        }
+        Ok(())
+    }
+
+    /// A comma separated list
+    fn set_list(&self, name: &str, field: &mut Vec<String>) {
+        if let Some(value) = self.0.get(name) {
+            *field = value.split(',').map(str::to_string).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config = Config::from_sources(None, &HashMap::new()).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.fetch_policy(), FetchPolicy::default());
+        assert_eq!(config.fetch_limits(), FetchLimits::default());
+        assert_eq!(config.decode_limits(), DecodeLimits::default());
+    }
+
+    #[test]
+    fn test_config_file_and_env() {
+        let file = r#"
+            [server]
+            host = "0.0.0.0"
+            port = 9000
+            workers = 2
+
+            [fetch]
+            allow_hosts = [" Images.Example.com "]
+            timeout_secs = 10
+
+            [decode]
+            downscale_above = 4096
+
+            [cache]
+            disk_dir = "/var/cache/overlay"
+
+            [gradient]
+            fade = 0.5
+            midpoint = 0.3
+        "#;
+        let env = vars(&[
+            ("OVERLAY_PORT", "9100"),
+            ("OVERLAY_DENY_HOSTS", "internal.example.com,"),
+            ("OVERLAY_ALLOW_PRIVATE", "true"),
+            ("OVERLAY_FADE", "0.8"),
+            ("OVERLAY_FONT_DIR", "fonts"),
+            ("OVERLAY_DISK_CACHE_TTL_SECS", "3600"),
+        ]);
+        let config = Config::from_sources(Some(("overlay.toml", file)), &env).unwrap();
+
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.server.workers, Some(2));
+        assert_eq!(
+            config.fetch_policy(),
+            FetchPolicy {
+                allow_hosts: vec!["images.example.com".to_string()],
+                deny_hosts: vec!["internal.example.com".to_string()],
+                allow_private: true,
+            }
+        );
+        assert_eq!(config.fetch_limits().timeout, Duration::from_secs(10));
+        assert_eq!(config.decode_limits().downscale_above, Some(4096));
+        assert_eq!(
+            config.cache.disk_dir,
+            Some(PathBuf::from("/var/cache/overlay"))
+        );
+        assert_eq!(config.cache.disk_ttl_secs, 3600);
+        assert_eq!(config.resources.font_dir, Some(PathBuf::from("fonts")));
+        assert_eq!(config.gradient.fade, Some(Fade(0.8)));
+        assert_eq!(config.gradient.midpoint, Some(Midpoint(0.3)));
+        assert_eq!(config.gradient.exponent, None);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let error = |file: Option<&str>, env: &[(&str, &str)]| {
+            Config::from_sources(file.map(|f| ("overlay.toml", f)), &vars(env))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(None, &[("OVERLAY_PORT", "http")]),
+            "invalid OVERLAY_PORT=http: invalid digit found in string"
+        );
+        assert_eq!(
+            error(None, &[("OVERLAY_FADE", "2")]),
+            "invalid OVERLAY_FADE=2: Allowed values are 0.0 to 1.0"
+        );
+        assert_eq!(
+            error(None, &[("OVERLAY_WORKERS", "0")]),
+            "invalid config: server.workers (OVERLAY_WORKERS) must be greater than 0"
+        );
+        assert_eq!(
+            error(None, &[("OVERLAY_TIMEOUT_SECS", "1")]),
+            "invalid config: fetch.timeout_secs (OVERLAY_TIMEOUT_SECS) must not be less than fetch.connect_timeout_secs"
+        );
+        let unknown = error(Some("[server]\nthreads = 4\n"), &[]);
+        assert!(
+            unknown.starts_with("invalid config overlay.toml:"),
+            "{}",
+            unknown
+        );
+        assert!(unknown.contains("unknown field `threads`"), "{}", unknown);
+        let range = error(Some("[gradient]\nexponent = 20.0\n"), &[]);
+        assert!(
+            range.contains("Allowed values are 0.1 to 10.0"),
+            "{}",
+            range
+        );
+    }
+}
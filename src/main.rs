@@ -1,23 +1,37 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::error::QueryPayloadError;
-use actix_web::{App, HttpResponse, HttpServer, middleware, web};
+use actix_web::http::header;
+use actix_web::{App, HttpMessage, HttpResponse, HttpServer, middleware, web};
 use async_trait::async_trait;
-use image::{ImageBuffer, Rgba};
-use serde::de::{self, Deserializer};
+use futures_util::StreamExt;
+use serde::de::{self, DeserializeOwned, Deserializer};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
-use std::io::Cursor;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use utoipa::{OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod animation;
+mod assets;
+mod cache;
+mod config;
+mod metadata;
+mod metrics;
 mod overlay;
+mod policy;
+mod text;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
 enum GradientType {
     Dominant,
     DominantBottom,
     UserDefined,
+    Stops,
 }
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 struct Fade(f32);
@@ -26,7 +40,7 @@ impl FromStr for Fade {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let v = s.parse::<f32>().map_err(|_| "Invalid fade")?;
-        if v > 1.0 || v < 0.0 {
+        if !(0.0..=1.0).contains(&v) {
             return Err("Allowed values are 0.0 to 1.0".to_string());
         }
         Ok(Fade(v))
@@ -43,6 +57,387 @@ impl PartialEq for Fade {
     }
 }
 
+/// Vertical position of the transparent band, 0.0 (top) to 1.0 (bottom)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Midpoint(f32);
+
+impl FromStr for Midpoint {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid midpoint")?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err("Allowed values are 0.0 to 1.0".to_string());
+        }
+        Ok(Midpoint(v))
+    }
+}
+
+/// Falloff curve of the gradient, 1.0 is linear and higher values keep the middle clearer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Exponent(f32);
+
+impl FromStr for Exponent {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid exponent")?;
+        if !(0.1..=10.0).contains(&v) {
+            return Err("Allowed values are 0.1 to 10.0".to_string());
+        }
+        Ok(Exponent(v))
+    }
+}
+
+/// Multiplier for the overlay alpha on one side of the gradient, 0.0 to 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Strength(f32);
+
+impl FromStr for Strength {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid strength")?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err("Allowed values are 0.0 to 1.0".to_string());
+        }
+        Ok(Strength(v))
+    }
+}
+
+/// The shape of the gradient, Linear uses angle and Radial uses cx, cy and radius
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum ShapeType {
+    Vertical,
+    Horizontal,
+    Linear,
+    Radial,
+    Vignette,
+}
+
+/// Angle of a linear gradient in degrees, 0.0 (top to bottom) to 360.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Angle(f32);
+
+impl FromStr for Angle {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid angle")?;
+        if !(0.0..=360.0).contains(&v) {
+            return Err("Allowed values are 0.0 to 360.0".to_string());
+        }
+        Ok(Angle(v))
+    }
+}
+
+/// Relative position in the image, 0.0 to 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Position(f32);
+
+impl FromStr for Position {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid position")?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err("Allowed values are 0.0 to 1.0".to_string());
+        }
+        Ok(Position(v))
+    }
+}
+
+/// Radius of a radial gradient relative to half the image diagonal, 0.01 to 2.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Radius(f32);
+
+impl FromStr for Radius {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid radius")?;
+        if !(0.01..=2.0).contains(&v) {
+            return Err("Allowed values are 0.01 to 2.0".to_string());
+        }
+        Ok(Radius(v))
+    }
+}
+
+/// The encoding of the returned image, when not given it is negotiated from the Accept header.
+/// Animated GIF and WebP sources stay animated with Webp and Gif output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+    Gif,
+}
+
+impl OutputFormat {
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+            OutputFormat::Webp => image::ImageFormat::WebP,
+            OutputFormat::Avif => image::ImageFormat::Avif,
+            OutputFormat::Gif => image::ImageFormat::Gif,
+        }
+    }
+
+    /// The format can hold all frames of an animated source
+    fn animated(self) -> bool {
+        matches!(self, OutputFormat::Webp | OutputFormat::Gif)
+    }
+
+    fn content_type(self) -> &'static str {
+        self.image_format().to_mime_type()
+    }
+
+    /// Pick the output format from an Accept header.
+    /// Only explicitly listed image types are considered, the one with the highest q value wins
+    /// and ties are broken by preferring the smaller encodings (avif, jpeg, webp). WebP output is
+    /// lossless, so it ranks below the lossy jpeg.
+    /// Wildcards or a missing header keep the default png output
+    fn negotiate(accept: Option<&str>) -> OutputFormat {
+        let Some(accept) = accept else {
+            return OutputFormat::Png;
+        };
+        let preference = [
+            OutputFormat::Avif,
+            OutputFormat::Jpeg,
+            OutputFormat::Webp,
+            OutputFormat::Png,
+        ];
+        let mut best: Option<(f32, usize)> = None;
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';');
+            let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let Some(rank) = preference.iter().position(|f| f.content_type() == mime) else {
+                continue;
+            };
+            if q <= 0.0 {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some((best_q, best_rank)) => q > best_q || (q == best_q && rank < best_rank),
+            };
+            if better {
+                best = Some((q, rank));
+            }
+        }
+        best.map(|(_, rank)| preference[rank])
+            .unwrap_or(OutputFormat::Png)
+    }
+}
+
+/// Encoding quality for the lossy formats, 1 (smallest) to 100 (best)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Quality(u8);
+
+impl FromStr for Quality {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<u8>().map_err(|_| "Invalid quality")?;
+        if !(1..=100).contains(&v) {
+            return Err("Allowed values are 1 to 100".to_string());
+        }
+        Ok(Quality(v))
+    }
+}
+
+/// How the source is fitted when both width and height are given
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum FitType {
+    Cover,
+    Contain,
+    Fill,
+    Inside,
+}
+
+impl From<FitType> for overlay::Fit {
+    fn from(fit: FitType) -> Self {
+        match fit {
+            FitType::Cover => overlay::Fit::Cover,
+            FitType::Contain => overlay::Fit::Contain,
+            FitType::Fill => overlay::Fit::Fill,
+            FitType::Inside => overlay::Fit::Inside,
+        }
+    }
+}
+
+/// Output width or height in pixels, 1 to 8192
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Dimension(u32);
+
+impl FromStr for Dimension {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<u32>().map_err(|_| "Invalid dimension")?;
+        if !(1..=overlay::MAX_DIMENSION).contains(&v) {
+            return Err(format!(
+                "Allowed dimensions are 1 to {}",
+                overlay::MAX_DIMENSION
+            ));
+        }
+        Ok(Dimension(v))
+    }
+}
+
+/// How the gradient color is combined with the image
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum BlendModeType {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    Color,
+    Luminosity,
+}
+
+impl From<BlendModeType> for overlay::BlendMode {
+    fn from(mode: BlendModeType) -> Self {
+        match mode {
+            BlendModeType::Normal => overlay::BlendMode::Normal,
+            BlendModeType::Multiply => overlay::BlendMode::Multiply,
+            BlendModeType::Screen => overlay::BlendMode::Screen,
+            BlendModeType::Overlay => overlay::BlendMode::Overlay,
+            BlendModeType::SoftLight => overlay::BlendMode::SoftLight,
+            BlendModeType::Color => overlay::BlendMode::Color,
+            BlendModeType::Luminosity => overlay::BlendMode::Luminosity,
+        }
+    }
+}
+
+/// Color space of the blending, Srgb blends the encoded values and Linear blends in linear light
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum BlendSpaceType {
+    Srgb,
+    Linear,
+}
+
+impl From<BlendSpaceType> for overlay::BlendSpace {
+    fn from(space: BlendSpaceType) -> Self {
+        match space {
+            BlendSpaceType::Srgb => overlay::BlendSpace::Srgb,
+            BlendSpaceType::Linear => overlay::BlendSpace::Linear,
+        }
+    }
+}
+
+/// Horizontal alignment of a text layer within its box
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum TextAlignType {
+    Left,
+    Center,
+    Right,
+}
+
+/// Vertical placement of a text layer within its box
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum VerticalAlignType {
+    Top,
+    Center,
+    Bottom,
+}
+
+/// Font size in pixels, 1.0 to 1000.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct FontSize(f32);
+
+impl FromStr for FontSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid font size")?;
+        if !(1.0..=1000.0).contains(&v) {
+            return Err("Allowed values are 1.0 to 1000.0".to_string());
+        }
+        Ok(FontSize(v))
+    }
+}
+
+/// Maximum number of lines of a text layer, 1 to 100
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct MaxLines(u32);
+
+impl FromStr for MaxLines {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<u32>().map_err(|_| "Invalid max lines")?;
+        if !(1..=100).contains(&v) {
+            return Err("Allowed values are 1 to 100".to_string());
+        }
+        Ok(MaxLines(v))
+    }
+}
+
+/// Where the watermark is placed in the image
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+enum WatermarkPositionType {
+    TopLeft,
+    TopRight,
+    Center,
+    BottomLeft,
+    BottomRight,
+}
+
+impl From<WatermarkPositionType> for overlay::WatermarkPosition {
+    fn from(position: WatermarkPositionType) -> Self {
+        match position {
+            WatermarkPositionType::TopLeft => overlay::WatermarkPosition::TopLeft,
+            WatermarkPositionType::TopRight => overlay::WatermarkPosition::TopRight,
+            WatermarkPositionType::Center => overlay::WatermarkPosition::Center,
+            WatermarkPositionType::BottomLeft => overlay::WatermarkPosition::BottomLeft,
+            WatermarkPositionType::BottomRight => overlay::WatermarkPosition::BottomRight,
+        }
+    }
+}
+
+/// Distance of the watermark to the edges relative to the image width, 0.0 to 0.5
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Margin(f32);
+
+impl FromStr for Margin {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid margin")?;
+        if !(0.0..=0.5).contains(&v) {
+            return Err("Allowed values are 0.0 to 0.5".to_string());
+        }
+        Ok(Margin(v))
+    }
+}
+
+/// Width of the watermark relative to the image width, 0.01 to 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Scale(f32);
+
+impl FromStr for Scale {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid scale")?;
+        if !(0.01..=1.0).contains(&v) {
+            return Err("Allowed values are 0.01 to 1.0".to_string());
+        }
+        Ok(Scale(v))
+    }
+}
+
+/// Opacity of the watermark, 0.0 to 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct Opacity(f32);
+
+impl FromStr for Opacity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<f32>().map_err(|_| "Invalid opacity")?;
+        if !(0.0..=1.0).contains(&v) {
+            return Err("Allowed values are 0.0 to 1.0".to_string());
+        }
+        Ok(Opacity(v))
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, ToSchema)]
 pub struct Rgb(pub String);
 
@@ -55,162 +450,1660 @@ impl FromStr for Rgb {
             return Err("Expected format: R,G,B".into());
         }
 
-        for part in &parts {
-            part.trim().parse::<u8>().map_err(|_| "Invalid RGB value")?;
+        for part in &parts {
+            part.trim().parse::<u8>().map_err(|_| "Invalid RGB value")?;
+        }
+        Ok(Rgb(s.to_string()))
+    }
+}
+
+impl Rgb {
+    pub fn to_tuple(&self) -> Result<(u8, u8, u8), String> {
+        let parts: Vec<&str> = self.0.split(',').collect();
+        if parts.len() != 3 {
+            return Err("Expected format: R,G,B".into());
+        }
+        let r = parts[0].trim().parse::<u8>().map_err(|_| "Invalid R")?;
+        let g = parts[1].trim().parse::<u8>().map_err(|_| "Invalid G")?;
+        let b = parts[2].trim().parse::<u8>().map_err(|_| "Invalid B")?;
+        Ok((r, g, b))
+    }
+}
+
+/// Color stops as position:r,g,b,alpha separated by ';', e.g. 0:20,20,40,0.9;1:0,0,0,0
+/// positions and alpha are 0.0 to 1.0
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, ToSchema)]
+pub struct Stops(pub String);
+
+impl FromStr for Stops {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_stops(s)?;
+        Ok(Stops(s.to_string()))
+    }
+}
+
+impl Stops {
+    pub fn to_color_stops(&self) -> Result<Vec<overlay::ColorStop>, String> {
+        parse_stops(&self.0)
+    }
+}
+
+fn parse_stops(s: &str) -> Result<Vec<overlay::ColorStop>, String> {
+    let stops = s
+        .split(';')
+        .filter(|stop| !stop.trim().is_empty())
+        .map(|stop| {
+            let (position, color) = stop
+                .split_once(':')
+                .ok_or("Expected format: position:R,G,B,alpha")?;
+            let position = position
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| "Invalid stop position")?;
+            if !(0.0..=1.0).contains(&position) {
+                return Err("Allowed stop positions are 0.0 to 1.0".to_string());
+            }
+            let parts: Vec<&str> = color.split(',').collect();
+            if parts.len() != 4 {
+                return Err("Expected format: position:R,G,B,alpha".into());
+            }
+            let r = parts[0].trim().parse::<u8>().map_err(|_| "Invalid R")?;
+            let g = parts[1].trim().parse::<u8>().map_err(|_| "Invalid G")?;
+            let b = parts[2].trim().parse::<u8>().map_err(|_| "Invalid B")?;
+            let alpha = parts[3]
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| "Invalid stop alpha")?;
+            if !(0.0..=1.0).contains(&alpha) {
+                return Err("Allowed stop alpha values are 0.0 to 1.0".to_string());
+            }
+            Ok(overlay::ColorStop {
+                position,
+                color: palette::Srgb::new(r, g, b),
+                alpha,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if stops.is_empty() {
+        return Err("At least one stop is required".into());
+    }
+    Ok(stops)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ImageQuery {
+    url: String,
+    #[serde(flatten)]
+    params: OverlayParams,
+}
+
+impl ImageQuery {
+    /// Key for the render cache, the url together with the normalized parameters
+    fn cache_key(&self, format: OutputFormat, quality: u8) -> String {
+        let normalized = ImageQuery {
+            url: self.url.clone(),
+            params: self.params.normalized(format, quality),
+        };
+        serde_json::to_string(&normalized).unwrap_or_default()
+    }
+}
+
+/// The parameters of an overlay, shared by the url and upload endpoints
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct OverlayParams {
+    gradient_variant: GradientType,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    rgb: Option<Rgb>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    fade: Option<Fade>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    stops: Option<Stops>,
+    #[serde(default)]
+    format: Option<OutputFormat>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    quality: Option<Quality>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    width: Option<Dimension>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    height: Option<Dimension>,
+    #[serde(default)]
+    fit: Option<FitType>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    midpoint: Option<Midpoint>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    exponent: Option<Exponent>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    top_strength: Option<Strength>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    bottom_strength: Option<Strength>,
+    #[serde(default)]
+    shape: Option<ShapeType>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    angle: Option<Angle>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    cx: Option<Position>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    cy: Option<Position>,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    radius: Option<Radius>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blend_mode: Option<BlendModeType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blend_space: Option<BlendSpaceType>,
+    /// Keep the transparency of the source, the overlay is only applied within it
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    preserve_alpha: Option<bool>,
+    /// Color (r,g,b) transparent parts are flattened onto for Jpeg output, defaults to white
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    background: Option<Rgb>,
+    /// Keep the EXIF metadata of the source in Jpeg, Png and Webp output, stripped by default
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    preserve_metadata: Option<bool>,
+    /// Url of the watermark image, fetched with the same policy as the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    watermark_url: Option<String>,
+    /// Name of an image in the assets directory to use as watermark
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    watermark_asset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    watermark_position: Option<WatermarkPositionType>,
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    watermark_margin: Option<Margin>,
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    watermark_scale: Option<Scale>,
+    #[serde(
+        default,
+        deserialize_with = "option_from_str_deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    watermark_opacity: Option<Opacity>,
+    /// Text layers, only available in the JSON body of POST /render
+    #[serde(default, skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    text: Vec<RenderText>,
+}
+
+impl OverlayParams {
+    /// Build the overlay options, fails with a message when a value required by the
+    /// gradient variant is missing
+    fn overlay_options(&self) -> Result<overlay::OverlayOptions, String> {
+        let gradient_variant = match self.gradient_variant {
+            GradientType::Dominant => overlay::GradientColorType::Dominant,
+            GradientType::DominantBottom => overlay::GradientColorType::DominantBottom,
+            GradientType::UserDefined => {
+                if let Some(rgb) = &self.rgb {
+                    let (r, g, b) = rgb.to_tuple()?;
+                    overlay::GradientColorType::UserSelected(r, g, b)
+                } else {
+                    return Err("Missing mandatory rgb values for user defined gradient".into());
+                }
+            }
+            GradientType::Stops => {
+                if let Some(stops) = &self.stops {
+                    overlay::GradientColorType::Stops(stops.to_color_stops()?)
+                } else {
+                    return Err("Missing mandatory stops for stops gradient".into());
+                }
+            }
+        };
+        Ok(overlay::OverlayOptions {
+            gradient_variant,
+            fade: self.fade.as_ref().map_or(1.0, |f| f.0),
+            gradient: self.gradient_spec(),
+            resize: self.resize(),
+            blending: overlay::Blending {
+                mode: self
+                    .blend_mode
+                    .map_or(overlay::BlendMode::Normal, Into::into),
+                space: self
+                    .blend_space
+                    .map_or(overlay::BlendSpace::Srgb, Into::into),
+                preserve_alpha: self.preserve_alpha.unwrap_or(false),
+            },
+            watermark: self.watermark()?,
+            text: self.text.iter().map(RenderText::text_layer).collect(),
+            animate: false,
+            preserve_metadata: self.preserve_metadata.unwrap_or(false),
+        })
+    }
+
+    /// Fill the gradient values left out of the request with the configured defaults, this is
+    /// done before the cache key is built so changing a default never serves an old render
+    fn with_defaults(mut self, defaults: &config::GradientDefaults) -> OverlayParams {
+        self.fade = self.fade.or_else(|| defaults.fade.clone());
+        self.midpoint = self.midpoint.or(defaults.midpoint);
+        self.exponent = self.exponent.or(defaults.exponent);
+        self.top_strength = self.top_strength.or(defaults.top_strength);
+        self.bottom_strength = self.bottom_strength.or(defaults.bottom_strength);
+        self
+    }
+
+    /// The parsed values with the defaults filled in, serialized they are in a fixed order so
+    /// equal requests share a cache key regardless of parameter order and formatting
+    fn normalized(&self, format: OutputFormat, quality: u8) -> OverlayParams {
+        let mut normalized = self.clone();
+        normalized.rgb = match self.gradient_variant {
+            GradientType::UserDefined => self
+                .rgb
+                .as_ref()
+                .and_then(|rgb| rgb.to_tuple().ok())
+                .map(|(r, g, b)| Rgb(format!("{},{},{}", r, g, b))),
+            _ => None,
+        };
+        normalized.stops = match self.gradient_variant {
+            GradientType::Stops => self
+                .stops
+                .as_ref()
+                .and_then(|stops| stops.to_color_stops().ok())
+                .map(|stops| {
+                    let stops: Vec<String> = stops
+                        .iter()
+                        .map(|s| {
+                            format!(
+                                "{}:{},{},{},{}",
+                                s.position, s.color.red, s.color.green, s.color.blue, s.alpha
+                            )
+                        })
+                        .collect();
+                    Stops(stops.join(";"))
+                }),
+            _ => None,
+        };
+        normalized.fade = Some(self.fade.clone().unwrap_or(Fade(1.0)));
+        normalized.format = Some(format);
+        normalized.quality = Some(Quality(quality));
+        normalized.blend_mode = self.blend_mode.filter(|m| *m != BlendModeType::Normal);
+        normalized.blend_space = self.blend_space.filter(|s| *s != BlendSpaceType::Srgb);
+        normalized.preserve_alpha = self.preserve_alpha.filter(|p| *p);
+        normalized.preserve_metadata = self.preserve_metadata.filter(|p| *p);
+        // the background only shows with transparent pixels in formats without alpha
+        normalized.background = match (normalized.preserve_alpha, format) {
+            (Some(true), OutputFormat::Jpeg) => {
+                let background = self.background();
+                Some(Rgb(format!(
+                    "{},{},{}",
+                    background.red, background.green, background.blue
+                )))
+            }
+            _ => None,
+        };
+        if let Ok(Some(watermark)) = self.watermark() {
+            normalized.watermark_position = self
+                .watermark_position
+                .or(Some(WatermarkPositionType::BottomRight));
+            normalized.watermark_margin = Some(Margin(watermark.margin));
+            normalized.watermark_scale = Some(Scale(watermark.scale));
+            normalized.watermark_opacity = Some(Opacity(watermark.opacity));
+        } else {
+            normalized.watermark_position = None;
+            normalized.watermark_margin = None;
+            normalized.watermark_scale = None;
+            normalized.watermark_opacity = None;
+        }
+        normalized
+    }
+
+    /// The output format, quality and if the format was negotiated from the Accept header,
+    /// the response only depends on the Accept header when no explicit format is given
+    fn output(&self, req: &actix_web::HttpRequest) -> (OutputFormat, u8, bool) {
+        let format = self.format.unwrap_or_else(|| {
+            OutputFormat::negotiate(
+                req.headers()
+                    .get(header::ACCEPT)
+                    .and_then(|v| v.to_str().ok()),
+            )
+        });
+        let quality = self.quality.unwrap_or(Quality(80)).0;
+        (format, quality, self.format.is_none())
+    }
+
+    fn gradient_spec(&self) -> overlay::GradientSpec {
+        let default = overlay::GradientSpec::default();
+        let shape = match self.shape.unwrap_or(ShapeType::Vertical) {
+            ShapeType::Vertical => overlay::GradientShape::Vertical,
+            ShapeType::Horizontal => overlay::GradientShape::Horizontal,
+            ShapeType::Linear => overlay::GradientShape::Linear {
+                angle: self.angle.map_or(0.0, |a| a.0),
+            },
+            ShapeType::Radial => overlay::GradientShape::Radial {
+                cx: self.cx.map_or(0.5, |c| c.0),
+                cy: self.cy.map_or(0.5, |c| c.0),
+                radius: self.radius.map_or(1.0, |r| r.0),
+            },
+            ShapeType::Vignette => overlay::GradientShape::Vignette,
+        };
+        overlay::GradientSpec {
+            shape,
+            midpoint: self.midpoint.map_or(default.midpoint, |m| m.0),
+            exponent: self.exponent.map_or(default.exponent, |e| e.0),
+            top_strength: self.top_strength.map_or(default.top_strength, |s| s.0),
+            bottom_strength: self
+                .bottom_strength
+                .map_or(default.bottom_strength, |s| s.0),
+        }
+    }
+
+    /// The color transparent pixels are flattened onto when the format has no alpha
+    fn background(&self) -> palette::Srgb<u8> {
+        self.background
+            .as_ref()
+            .and_then(|rgb| rgb.to_tuple().ok())
+            .map_or(palette::Srgb::new(255, 255, 255), |(r, g, b)| {
+                palette::Srgb::new(r, g, b)
+            })
+    }
+
+    fn watermark(&self) -> Result<Option<overlay::Watermark>, String> {
+        let source = match (&self.watermark_url, &self.watermark_asset) {
+            (Some(_), Some(_)) => {
+                return Err("Use either watermark_url or watermark_asset, not both".into());
+            }
+            (Some(url), None) => overlay::WatermarkSource::Url(url.clone()),
+            (None, Some(asset)) => overlay::WatermarkSource::Asset(asset.clone()),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(overlay::Watermark {
+            source,
+            position: self
+                .watermark_position
+                .unwrap_or(WatermarkPositionType::BottomRight)
+                .into(),
+            margin: self.watermark_margin.map_or(0.03, |m| m.0),
+            scale: self.watermark_scale.map_or(0.15, |s| s.0),
+            opacity: self.watermark_opacity.map_or(1.0, |o| o.0),
+        }))
+    }
+
+    fn resize(&self) -> Option<overlay::Resize> {
+        if self.width.is_none() && self.height.is_none() {
+            return None;
+        }
+        Some(overlay::Resize {
+            width: self.width.map(|w| w.0),
+            height: self.height.map(|h| h.0),
+            fit: self.fit.unwrap_or(FitType::Cover).into(),
+        })
+    }
+}
+
+/// Deserialize a JSON number with the validation of the FromStr implementation of the
+/// query parameter type
+fn option_from_number_deserialize<'a, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'a>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Option::<serde_json::Number>::deserialize(deserializer)? {
+        Some(n) => T::from_str(&n.to_string())
+            .map(Some)
+            .map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn unit_interval_deserialize<'a, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    let v = f32::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&v) {
+        return Err(de::Error::custom("Allowed values are 0.0 to 1.0"));
+    }
+    Ok(v)
+}
+
+/// Most text layers of a render request
+const MAX_TEXT_LAYERS: usize = 16;
+/// Most characters in the content of a text layer
+const MAX_TEXT_CHARS: usize = 2000;
+
+fn text_layers_deserialize<'a, D>(deserializer: D) -> Result<Vec<RenderText>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    let layers = Vec::<RenderText>::deserialize(deserializer)?;
+    if layers.len() > MAX_TEXT_LAYERS {
+        return Err(de::Error::custom(format!(
+            "At most {} text layers are allowed",
+            MAX_TEXT_LAYERS
+        )));
+    }
+    Ok(layers)
+}
+
+fn text_content_deserialize<'a, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    let content = String::deserialize(deserializer)?;
+    if content.chars().count() > MAX_TEXT_CHARS {
+        return Err(de::Error::custom(format!(
+            "At most {} characters are allowed",
+            MAX_TEXT_CHARS
+        )));
+    }
+    Ok(content)
+}
+
+/// JSON body of POST /render
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderRequest {
+    source: RenderSource,
+    gradient: RenderGradient,
+    #[serde(default)]
+    output: RenderOutput,
+    #[serde(default)]
+    watermark: Option<RenderWatermark>,
+    /// Text drawn on top of the overlay, in order, at most 16 layers
+    #[serde(default, deserialize_with = "text_layers_deserialize")]
+    text: Vec<RenderText>,
+}
+
+/// A logo composited on the gradient, either url or asset is required
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderWatermark {
+    /// Url of the image, fetched with the same policy as the source
+    #[serde(default)]
+    url: Option<String>,
+    /// Name of an image in the assets directory
+    #[serde(default)]
+    asset: Option<String>,
+    /// Defaults to BottomRight
+    #[serde(default)]
+    position: Option<WatermarkPositionType>,
+    /// Defaults to 0.03
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    margin: Option<Margin>,
+    /// Defaults to 0.15
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    scale: Option<Scale>,
+    /// Defaults to 1.0
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    opacity: Option<Opacity>,
+}
+
+/// Where the source image is fetched from
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderSource {
+    url: String,
+}
+
+/// The gradient color and geometry, the values have the same ranges and defaults as the
+/// query parameters of GET /image
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderGradient {
+    variant: GradientType,
+    /// Color of the UserDefined variant
+    #[serde(default)]
+    rgb: Option<[u8; 3]>,
+    /// Color stops of the Stops variant
+    #[serde(default)]
+    stops: Option<Vec<RenderStop>>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    fade: Option<Fade>,
+    #[serde(default)]
+    shape: Option<ShapeType>,
+    #[serde(default)]
+    blend_mode: Option<BlendModeType>,
+    #[serde(default)]
+    blend_space: Option<BlendSpaceType>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    midpoint: Option<Midpoint>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    exponent: Option<Exponent>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    top_strength: Option<Strength>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    bottom_strength: Option<Strength>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    angle: Option<Angle>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    cx: Option<Position>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    cy: Option<Position>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    radius: Option<Radius>,
+}
+
+/// A color with alpha (0.0 to 1.0) at a position (0.0 to 1.0) along the gradient
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderStop {
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    position: f32,
+    color: [u8; 3],
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    alpha: f32,
+}
+
+/// Format and size of the output, the format is negotiated from the Accept header when omitted
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderOutput {
+    #[serde(default)]
+    format: Option<OutputFormat>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    quality: Option<Quality>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    width: Option<Dimension>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    height: Option<Dimension>,
+    #[serde(default)]
+    fit: Option<FitType>,
+    /// Keep the transparency of the source, the overlay is only applied within it
+    #[serde(default)]
+    preserve_alpha: Option<bool>,
+    /// Color transparent parts are flattened onto for Jpeg output, defaults to white
+    #[serde(default)]
+    background: Option<[u8; 3]>,
+    /// Keep the EXIF metadata of the source in Jpeg, Png and Webp output, stripped by default
+    #[serde(default)]
+    preserve_metadata: Option<bool>,
+}
+
+/// A text layer, the box places it in the image and the lines are wrapped to its width
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderText {
+    /// At most 2000 characters
+    #[serde(deserialize_with = "text_content_deserialize")]
+    content: String,
+    /// Name of a font in the font directory, the file name without the extension
+    font: String,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    size: Option<FontSize>,
+    /// Defaults to white
+    #[serde(default)]
+    color: Option<[u8; 3]>,
+    #[serde(default)]
+    align: Option<TextAlignType>,
+    #[serde(default)]
+    vertical_align: Option<VerticalAlignType>,
+    /// Defaults to the image with a 5% margin
+    #[serde(default, rename = "box")]
+    area: Option<RenderBox>,
+    #[serde(default, deserialize_with = "option_from_number_deserialize")]
+    max_lines: Option<MaxLines>,
+}
+
+/// Position and size as fractions (0.0 to 1.0) of the image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct RenderBox {
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    x: f32,
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    y: f32,
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    width: f32,
+    #[serde(deserialize_with = "unit_interval_deserialize")]
+    height: f32,
+}
+
+impl RenderText {
+    fn text_layer(&self) -> text::TextLayer {
+        let area = self.area.unwrap_or(RenderBox {
+            x: 0.05,
+            y: 0.05,
+            width: 0.9,
+            height: 0.9,
+        });
+        let [r, g, b] = self.color.unwrap_or([255, 255, 255]);
+        text::TextLayer {
+            content: self.content.clone(),
+            font: self.font.clone(),
+            size: self.size.map_or(48.0, |s| s.0),
+            color: palette::Srgb::new(r, g, b),
+            align: match self.align.unwrap_or(TextAlignType::Left) {
+                TextAlignType::Left => text::TextAlign::Left,
+                TextAlignType::Center => text::TextAlign::Center,
+                TextAlignType::Right => text::TextAlign::Right,
+            },
+            vertical_align: match self.vertical_align.unwrap_or(VerticalAlignType::Bottom) {
+                VerticalAlignType::Top => text::VerticalAlign::Top,
+                VerticalAlignType::Center => text::VerticalAlign::Center,
+                VerticalAlignType::Bottom => text::VerticalAlign::Bottom,
+            },
+            area: text::TextBox {
+                x: area.x,
+                y: area.y,
+                width: area.width,
+                height: area.height,
+            },
+            max_lines: self.max_lines.map(|m| m.0),
+        }
+    }
+}
+
+impl RenderRequest {
+    /// The equivalent query of GET /image, so both share the pipeline and the render cache.
+    /// Fails with the path and message when a value required by the variant is missing
+    fn into_query(self) -> Result<ImageQuery, (String, String)> {
+        let gradient = self.gradient;
+        let rgb = match (&gradient.variant, gradient.rgb) {
+            (GradientType::UserDefined, None) => {
+                return Err((
+                    "gradient.rgb".into(),
+                    "Missing mandatory rgb values for user defined gradient".into(),
+                ));
+            }
+            (_, rgb) => rgb.map(|[r, g, b]| Rgb(format!("{},{},{}", r, g, b))),
+        };
+        let stops = match (&gradient.variant, gradient.stops) {
+            (GradientType::Stops, None) => {
+                return Err((
+                    "gradient.stops".into(),
+                    "Missing mandatory stops for stops gradient".into(),
+                ));
+            }
+            (_, Some(stops)) if stops.is_empty() => {
+                return Err((
+                    "gradient.stops".into(),
+                    "At least one stop is required".into(),
+                ));
+            }
+            (_, stops) => stops.map(|stops| {
+                let stops: Vec<String> = stops
+                    .iter()
+                    .map(|s| {
+                        let [r, g, b] = s.color;
+                        format!("{}:{},{},{},{}", s.position, r, g, b, s.alpha)
+                    })
+                    .collect();
+                Stops(stops.join(";"))
+            }),
+        };
+        let watermark = match self.watermark {
+            Some(watermark) if watermark.url.is_some() == watermark.asset.is_some() => {
+                return Err((
+                    "watermark".into(),
+                    "Either url or asset is required for the watermark".into(),
+                ));
+            }
+            watermark => watermark,
+        };
+        Ok(ImageQuery {
+            url: self.source.url,
+            params: OverlayParams {
+                gradient_variant: gradient.variant,
+                rgb,
+                fade: gradient.fade,
+                stops,
+                format: self.output.format,
+                quality: self.output.quality,
+                width: self.output.width,
+                height: self.output.height,
+                fit: self.output.fit,
+                preserve_alpha: self.output.preserve_alpha,
+                background: self
+                    .output
+                    .background
+                    .map(|[r, g, b]| Rgb(format!("{},{},{}", r, g, b))),
+                preserve_metadata: self.output.preserve_metadata,
+                midpoint: gradient.midpoint,
+                exponent: gradient.exponent,
+                top_strength: gradient.top_strength,
+                bottom_strength: gradient.bottom_strength,
+                shape: gradient.shape,
+                angle: gradient.angle,
+                cx: gradient.cx,
+                cy: gradient.cy,
+                radius: gradient.radius,
+                blend_mode: gradient.blend_mode,
+                blend_space: gradient.blend_space,
+                watermark_url: watermark.as_ref().and_then(|w| w.url.clone()),
+                watermark_asset: watermark.as_ref().and_then(|w| w.asset.clone()),
+                watermark_position: watermark.as_ref().and_then(|w| w.position),
+                watermark_margin: watermark.as_ref().and_then(|w| w.margin),
+                watermark_scale: watermark.as_ref().and_then(|w| w.scale),
+                watermark_opacity: watermark.as_ref().and_then(|w| w.opacity),
+                text: self.text,
+            },
+        })
+    }
+}
+
+/// Number of colors to extract for the palette, 1 to 16
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+struct ClusterCount(usize);
+
+impl FromStr for ClusterCount {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = s.parse::<usize>().map_err(|_| "Invalid k")?;
+        if !(1..=16).contains(&v) {
+            return Err("Allowed values are 1 to 16".to_string());
+        }
+        Ok(ClusterCount(v))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct PaletteQuery {
+    url: String,
+    #[serde(default, deserialize_with = "option_from_str_deserialize")]
+    k: Option<ClusterCount>,
+}
+
+/// A color of the palette, the share is the fraction (0.0 to 1.0) of pixels closest to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+struct PaletteColorResponse {
+    hex: String,
+    rgb: [u8; 3],
+    lab: [f32; 3],
+    share: f32,
+}
+
+impl From<&overlay::PaletteColor> for PaletteColorResponse {
+    fn from(c: &overlay::PaletteColor) -> Self {
+        PaletteColorResponse {
+            hex: hex_color(c.color),
+            rgb: [c.color.red, c.color.green, c.color.blue],
+            lab: [c.lab.l, c.lab.a, c.lab.b],
+            share: c.share,
+        }
+    }
+}
+
+/// The palette sorted by share, largest first
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct PaletteResponse {
+    colors: Vec<PaletteColorResponse>,
+}
+
+/// Metadata of an overlay, the selected gradient color and the dimensions of the source
+/// before and the output after resizing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+struct ImageInfoResponse {
+    color: String,
+    rgb: [u8; 3],
+    source_width: u32,
+    source_height: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<&overlay::OverlayInfo> for ImageInfoResponse {
+    fn from(info: &overlay::OverlayInfo) -> Self {
+        ImageInfoResponse {
+            color: hex_color(info.color),
+            rgb: [info.color.red, info.color.green, info.color.blue],
+            source_width: info.source_width,
+            source_height: info.source_height,
+            width: info.width,
+            height: info.height,
+        }
+    }
+}
+
+fn option_from_str_deserialize<'a, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'a>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let opt = Option::<String>::deserialize(deserializer)?;
+    match opt {
+        Some(s) => T::from_str(&s).map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[async_trait]
+pub trait ImageGenerator: Send + Sync {
+    async fn generate_from_url(
+        &self,
+        url: String,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::GeneratedImage, overlay::OverlayError>;
+
+    async fn generate_from_bytes(
+        &self,
+        image: Vec<u8>,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::GeneratedImage, overlay::OverlayError>;
+
+    async fn info_from_url(
+        &self,
+        url: String,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::OverlayInfo, overlay::OverlayError>;
+
+    async fn palette_from_url(
+        &self,
+        url: String,
+        k: usize,
+    ) -> Result<Vec<overlay::PaletteColor>, overlay::OverlayError>;
+}
+
+pub struct RealImageGenerator {
+    manager: overlay::Manager,
+}
+
+#[async_trait]
+impl ImageGenerator for RealImageGenerator {
+    async fn generate_from_url(
+        &self,
+        url: String,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+        self.manager.generate_from_url(url, options).await
+    }
+
+    async fn generate_from_bytes(
+        &self,
+        image: Vec<u8>,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+        self.manager.generate_from_bytes(&image, options).await
+    }
+
+    async fn info_from_url(
+        &self,
+        url: String,
+        options: overlay::OverlayOptions,
+    ) -> Result<overlay::OverlayInfo, overlay::OverlayError> {
+        self.manager.info_from_url(url, options).await
+    }
+
+    async fn palette_from_url(
+        &self,
+        url: String,
+        k: usize,
+    ) -> Result<Vec<overlay::PaletteColor>, overlay::OverlayError> {
+        self.manager.palette_from_url(url, k).await
+    }
+}
+
+/// JSON body returned when the image could not be generated, path points to the invalid
+/// value of a JSON request
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+struct ErrorResponse {
+    error: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+/// Map an overlay error to the status code and JSON body returned to the client
+/// An upstream 404 is passed on as 404, other upstream failures are reported as 502
+/// or 504 when the upstream did not answer in time
+fn overlay_error_response(err: &overlay::OverlayError) -> HttpResponse {
+    use overlay::OverlayError;
+    let (mut builder, error) = match err {
+        OverlayError::InvalidUrl(_) => (HttpResponse::BadRequest(), "invalid_url"),
+        OverlayError::Forbidden(_) => (HttpResponse::Forbidden(), "forbidden_url"),
+        OverlayError::UpstreamStatus(status) if status.as_u16() == 404 => {
+            (HttpResponse::NotFound(), "upstream_not_found")
+        }
+        OverlayError::UpstreamStatus(_) => (HttpResponse::BadGateway(), "upstream_status"),
+        OverlayError::ConnectTimeout(_) => {
+            (HttpResponse::GatewayTimeout(), "upstream_connect_timeout")
+        }
+        OverlayError::Timeout(_) => (HttpResponse::GatewayTimeout(), "upstream_timeout"),
+        OverlayError::TooManyRedirects(_) => (HttpResponse::BadGateway(), "too_many_redirects"),
+        OverlayError::BodyTooLarge(_) => (HttpResponse::PayloadTooLarge(), "upstream_too_large"),
+        OverlayError::Fetch(_) => (HttpResponse::BadGateway(), "fetch_failed"),
+        OverlayError::BodyRead(_) => (HttpResponse::BadGateway(), "body_read_failed"),
+        OverlayError::ImageTooLarge(_) => (HttpResponse::PayloadTooLarge(), "image_too_large"),
+        OverlayError::UnknownFont(_) => (HttpResponse::BadRequest(), "unknown_font"),
+        OverlayError::UnknownAsset(_) => (HttpResponse::BadRequest(), "unknown_asset"),
+        OverlayError::Decode(_) => (HttpResponse::UnprocessableEntity(), "unsupported_image"),
+        OverlayError::Encode(_) => (HttpResponse::InternalServerError(), "encode_failed"),
+    };
+    builder.json(ErrorResponse {
+        error: error.to_string(),
+        message: err.to_string(),
+        path: None,
+    })
+}
+
+/// Parse the query string, invalid queries are answered with 400
+fn parse_query<T: DeserializeOwned>(req: &actix_web::HttpRequest) -> Result<T, HttpResponse> {
+    web::Query::<T>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .map_err(|e| {
+            let msg = match &e {
+                QueryPayloadError::Deserialize(inner) => inner.to_string(),
+                _ => e.to_string(),
+            };
+            HttpResponse::BadRequest().body(format!("Invalid query: {}", msg))
+        })
+}
+
+fn hex_color(color: palette::Srgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.red, color.green, color.blue)
+}
+
+#[utoipa::path(
+    get,
+    path = "/image",
+    params(
+        ("url" = String, Query, description = "Image URL"),
+        ("gradient_variant" = GradientType, Query, description = "Gradient type"),
+        ("rgb" = Option<Rgb>, Query, description = "Three RGB values (0-255) for user-defined gradient: r,g,b"),
+        ("fade" = Option<Fade>, Query, description = "Fade value between 0.0 and 1.0"),
+        ("stops" = Option<Stops>, Query, description = "Color stops for the Stops gradient as position:r,g,b,alpha separated by ';', e.g. 0:20,20,40,0.9;0.5:0,0,0,0;1:200,30,30,0.8"),
+        ("format" = Option<OutputFormat>, Query, description = "Output format, when omitted it is negotiated from the Accept header (image/avif, then image/jpeg, then the lossless image/webp on equal q values) and defaults to Png"),
+        ("quality" = Option<Quality>, Query, description = "Quality between 1 and 100 for Jpeg and Avif output, defaults to 80"),
+        ("width" = Option<Dimension>, Query, description = "Output width in pixels, the source is resized before the overlay is applied"),
+        ("height" = Option<Dimension>, Query, description = "Output height in pixels, the source is resized before the overlay is applied"),
+        ("fit" = Option<FitType>, Query, description = "How the source is fitted when both width and height are given, defaults to Cover"),
+        ("midpoint" = Option<Midpoint>, Query, description = "Vertical position between 0.0 (top) and 1.0 (bottom) where the overlay is transparent, defaults to 0.4"),
+        ("exponent" = Option<Exponent>, Query, description = "Falloff curve between 0.1 and 10.0, 1.0 is linear, defaults to 2.0"),
+        ("top_strength" = Option<Strength>, Query, description = "Alpha multiplier between 0.0 and 1.0 for the top part, defaults to 1.0"),
+        ("bottom_strength" = Option<Strength>, Query, description = "Alpha multiplier between 0.0 and 1.0 for the bottom part, combined with fade, defaults to 1.0"),
+        ("shape" = Option<ShapeType>, Query, description = "Gradient shape, defaults to Vertical"),
+        ("angle" = Option<Angle>, Query, description = "Angle in degrees for the Linear shape, 0.0 is top to bottom and 90.0 left to right"),
+        ("cx" = Option<Position>, Query, description = "Horizontal center between 0.0 and 1.0 for the Radial shape, defaults to 0.5"),
+        ("cy" = Option<Position>, Query, description = "Vertical center between 0.0 and 1.0 for the Radial shape, defaults to 0.5"),
+        ("radius" = Option<Radius>, Query, description = "Radius relative to half the image diagonal for the Radial shape, defaults to 1.0")
+    ),
+    responses(
+        (status = 200, description = "Image returned in the requested or negotiated format",
+            headers(
+                ("X-Cache" = String, description = "HIT when the image was served from the render cache, otherwise MISS"),
+                ("X-Overlay-Color" = String, description = "Selected gradient color as #rrggbb"),
+                ("X-Source-Width" = u32, description = "Width of the source image before resizing"),
+                ("X-Source-Height" = u32, description = "Height of the source image before resizing")
+            ),
+            content(
+                (Vec<u8> = "image/png"),
+                (Vec<u8> = "image/jpeg"),
+                (Vec<u8> = "image/webp"),
+                (Vec<u8> = "image/avif")
+            )
+        ),
+        (status = 400, description = "Invalid query parameters or image url", body = ErrorResponse),
+        (status = 403, description = "Image url refused by the fetch policy", body = ErrorResponse),
+        (status = 404, description = "Upstream image not found", body = ErrorResponse),
+        (status = 413, description = "Upstream image is larger than the allowed size or dimensions", body = ErrorResponse),
+        (status = 422, description = "Upstream content is not a supported image", body = ErrorResponse),
+        (status = 500, description = "Image encoding failed", body = ErrorResponse),
+        (status = 502, description = "Upstream image could not be fetched or redirected too often", body = ErrorResponse),
+        (status = 504, description = "Upstream connection or image fetch timed out", body = ErrorResponse)
+    )
+)]
+
+pub async fn image_handler(
+    req: actix_web::HttpRequest,
+    generator: web::Data<dyn ImageGenerator>,
+    cache: web::Data<cache::RenderCache>,
+    defaults: web::Data<config::GradientDefaults>,
+) -> HttpResponse {
+    let query: ImageQuery = match parse_query(&req) {
+        Ok(q) => q,
+        Err(resp) => return resp,
+    };
+    render_query(&req, query, &generator, &cache, &defaults).await
+}
+
+/// Render the image for the query or serve it from the render cache
+async fn render_query(
+    req: &actix_web::HttpRequest,
+    mut query: ImageQuery,
+    generator: &web::Data<dyn ImageGenerator>,
+    cache: &web::Data<cache::RenderCache>,
+    defaults: &config::GradientDefaults,
+) -> HttpResponse {
+    query.params = query.params.with_defaults(defaults);
+    req.extensions_mut()
+        .insert(query.params.gradient_variant.clone());
+    let mut options = match query.params.overlay_options() {
+        Ok(options) => options,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let (format, quality, negotiated) = query.params.output(req);
+    options.animate = format.animated();
+    let background = query.params.background();
+    let key = query.cache_key(format, quality);
+    if let Some(cached) = cache.get(&key).await {
+        return image_response(&cached, negotiated, Some("HIT"));
+    }
+    let generated = match generator.generate_from_url(query.url, options).await {
+        Ok(generated) => generated,
+        Err(e) => {
+            log::warn!("image generation failed: {}", e);
+            return overlay_error_response(&e);
+        }
+    };
+
+    match encode_generated(&generated, format, quality, background) {
+        Ok(data) => {
+            let rendered = cache::CachedImage {
+                body: data.into(),
+                content_type: format.content_type(),
+                info: generated.info,
+            };
+            cache.insert(key, rendered.clone()).await;
+            image_response(&rendered, negotiated, Some("MISS"))
         }
-        Ok(Rgb(s.to_string()))
+        Err(e) => overlay_error_response(&e),
     }
 }
 
-impl Rgb {
-    pub fn to_tuple(&self) -> Result<(u8, u8, u8), String> {
-        let parts: Vec<&str> = self.0.split(',').collect();
-        if parts.len() != 3 {
-            return Err("Expected format: R,G,B".into());
+/// Encode the generated image, animations are kept when the output format supports them
+fn encode_generated(
+    generated: &overlay::GeneratedImage,
+    format: OutputFormat,
+    quality: u8,
+    background: palette::Srgb<u8>,
+) -> Result<Vec<u8>, overlay::OverlayError> {
+    let start = std::time::Instant::now();
+    let encoded = match &generated.animation {
+        Some(animation) if format.animated() => {
+            animation::encode_animation(animation, format.image_format())
         }
-        let r = parts[0].trim().parse::<u8>().map_err(|_| "Invalid R")?;
-        let g = parts[1].trim().parse::<u8>().map_err(|_| "Invalid G")?;
-        let b = parts[2].trim().parse::<u8>().map_err(|_| "Invalid B")?;
-        Ok((r, g, b))
+        _ => overlay::encode_image(
+            &generated.image,
+            format.image_format(),
+            quality,
+            background,
+            &generated.metadata,
+        ),
+    };
+    metrics::observe_phase(metrics::Phase::Encode, start.elapsed());
+    encoded
+}
+
+/// Answer an invalid JSON request with 400 and the path to the invalid value
+fn invalid_request(path: String, message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorResponse {
+        error: "invalid_request".to_string(),
+        message: format!("{}: {}", path, message),
+        path: Some(path),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/render",
+    request_body(content = RenderRequest, content_type = "application/json"),
+    responses(
+        (status = 200, description = "Image returned in the requested or negotiated format",
+            headers(
+                ("X-Cache" = String, description = "HIT when the image was served from the render cache, otherwise MISS"),
+                ("X-Overlay-Color" = String, description = "Selected gradient color as #rrggbb"),
+                ("X-Source-Width" = u32, description = "Width of the source image before resizing"),
+                ("X-Source-Height" = u32, description = "Height of the source image before resizing")
+            ),
+            content(
+                (Vec<u8> = "image/png"),
+                (Vec<u8> = "image/jpeg"),
+                (Vec<u8> = "image/webp"),
+                (Vec<u8> = "image/avif")
+            )
+        ),
+        (status = 400, description = "Invalid request, path points to the invalid value", body = ErrorResponse),
+        (status = 403, description = "Image url refused by the fetch policy", body = ErrorResponse),
+        (status = 404, description = "Upstream image not found", body = ErrorResponse),
+        (status = 413, description = "Upstream image is larger than the allowed size or dimensions", body = ErrorResponse),
+        (status = 422, description = "Upstream content is not a supported image", body = ErrorResponse),
+        (status = 500, description = "Image encoding failed", body = ErrorResponse),
+        (status = 502, description = "Upstream image could not be fetched or redirected too often", body = ErrorResponse),
+        (status = 504, description = "Upstream connection or image fetch timed out", body = ErrorResponse)
+    )
+)]
+
+/// The JSON variant of GET /image, it shares the render cache with it
+pub async fn render_handler(
+    req: actix_web::HttpRequest,
+    body: web::Bytes,
+    generator: web::Data<dyn ImageGenerator>,
+    cache: web::Data<cache::RenderCache>,
+    defaults: web::Data<config::GradientDefaults>,
+) -> HttpResponse {
+    let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+    let request: RenderRequest = match serde_path_to_error::deserialize(deserializer) {
+        Ok(request) => request,
+        Err(e) => return invalid_request(e.path().to_string(), e.inner().to_string()),
+    };
+    let query = match request.into_query() {
+        Ok(query) => query,
+        Err((path, message)) => return invalid_request(path, message),
+    };
+    render_query(&req, query, &generator, &cache, &defaults).await
+}
+
+/// Build the image response with the metadata headers, X-Cache tells if it came from the cache
+/// and is left out for responses that are never cached
+fn image_response(
+    rendered: &cache::CachedImage,
+    negotiated: bool,
+    cache_status: Option<&str>,
+) -> HttpResponse {
+    let info = &rendered.info;
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(rendered.content_type)
+        .insert_header(("X-Overlay-Color", hex_color(info.color)))
+        .insert_header(("X-Source-Width", info.source_width.to_string()))
+        .insert_header(("X-Source-Height", info.source_height.to_string()));
+    if let Some(cache_status) = cache_status {
+        response.insert_header(("X-Cache", cache_status.to_string()));
+    }
+    if negotiated {
+        response.insert_header((header::VARY, "Accept"));
     }
+    response.body(rendered.body.clone())
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
-struct ImageQuery {
-    url: String,
-    gradient_variant: GradientType,
-    #[serde(default, deserialize_with = "option_from_str_deserialize")]
-    rgb: Option<Rgb>,
-    #[serde(default, deserialize_with = "option_from_str_deserialize")]
-    fade: Option<Fade>,
+/// Largest accepted upload, the same as the default size limit for fetched images
+const MAX_UPLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+/// Most text fields accepted in an upload form, more than there are parameters
+const MAX_UPLOAD_FIELDS: usize = 64;
+
+fn upload_error(
+    mut builder: actix_web::HttpResponseBuilder,
+    error: &str,
+    message: &str,
+) -> HttpResponse {
+    builder.json(ErrorResponse {
+        error: error.to_string(),
+        message: message.to_string(),
+        path: None,
+    })
 }
 
-fn option_from_str_deserialize<'a, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+/// Read a body or multipart field, failing with 413 when the request is larger than
+/// MAX_UPLOAD_BYTES, total is the number of bytes read so far across all fields
+async fn read_limited<S, E>(mut stream: S, total: &mut usize) -> Result<Vec<u8>, HttpResponse>
 where
-    D: Deserializer<'a>,
-    T: FromStr,
-    T::Err: std::fmt::Display,
+    S: futures_util::Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: fmt::Display,
 {
-    let opt = Option::<String>::deserialize(deserializer)?;
-    match opt {
-        Some(s) => T::from_str(&s).map(Some).map_err(de::Error::custom),
-        None => Ok(None),
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            upload_error(HttpResponse::BadRequest(), "invalid_upload", &e.to_string())
+        })?;
+        *total += chunk.len();
+        if *total > MAX_UPLOAD_BYTES {
+            return Err(upload_error(
+                HttpResponse::PayloadTooLarge(),
+                "upload_too_large",
+                &format!(
+                    "Upload is larger than the allowed {} bytes",
+                    MAX_UPLOAD_BYTES
+                ),
+            ));
+        }
+        data.extend_from_slice(&chunk);
     }
+    Ok(data)
 }
 
-#[async_trait]
-pub trait ImageGenerator: Send + Sync {
-    async fn generate_from_url(
-        &self,
-        url: String,
-        gradient_variant: overlay::GradientColorType,
-        fade: f32,
-    ) -> ImageBuffer<Rgba<u8>, Vec<u8>>;
+/// Read the uploaded image and, for multipart forms, the text fields. The image is the raw
+/// body of an image/* request or the part of a multipart form that has a file name or is named file
+async fn read_upload(
+    req: &actix_web::HttpRequest,
+    payload: web::Payload,
+) -> Result<(Vec<u8>, Vec<(String, String)>), HttpResponse> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if content_type.starts_with("image/") {
+        return Ok((read_limited(payload, &mut 0).await?, Vec::new()));
+    }
+    if !content_type.starts_with("multipart/form-data") {
+        return Err(upload_error(
+            HttpResponse::UnsupportedMediaType(),
+            "unsupported_media_type",
+            "Expected an image/* body or a multipart/form-data form",
+        ));
+    }
+    let mut multipart = actix_multipart::Multipart::new(req.headers(), payload);
+    let mut image = None;
+    let mut fields = Vec::new();
+    let mut total = 0;
+    while let Some(field) = multipart.next().await {
+        let field = field.map_err(|e| {
+            upload_error(HttpResponse::BadRequest(), "invalid_upload", &e.to_string())
+        })?;
+        let name = field.name().unwrap_or_default().to_string();
+        let is_file = name == "file"
+            || field
+                .content_disposition()
+                .is_some_and(|cd| cd.get_filename().is_some());
+        if !is_file && fields.len() == MAX_UPLOAD_FIELDS {
+            return Err(upload_error(
+                HttpResponse::BadRequest(),
+                "invalid_upload",
+                &format!("The form has more than {} fields", MAX_UPLOAD_FIELDS),
+            ));
+        }
+        let data = read_limited(field, &mut total).await?;
+        if is_file {
+            image = Some(data);
+        } else {
+            let value = String::from_utf8(data).map_err(|_| {
+                upload_error(
+                    HttpResponse::BadRequest(),
+                    "invalid_upload",
+                    &format!("Field {} is not valid utf-8", name),
+                )
+            })?;
+            fields.push((name, value));
+        }
+    }
+    match image {
+        Some(image) => Ok((image, fields)),
+        None => Err(upload_error(
+            HttpResponse::BadRequest(),
+            "missing_image",
+            "The form has no file part",
+        )),
+    }
 }
 
-pub struct RealImageGenerator {
-    manager: overlay::Manager,
+/// Parse the overlay parameters from the query string and the form fields, form fields
+/// take precedence
+fn parse_upload_params(
+    req: &actix_web::HttpRequest,
+    fields: Vec<(String, String)>,
+) -> Result<OverlayParams, HttpResponse> {
+    let mut params: serde_json::Map<String, serde_json::Value> =
+        parse_query::<HashMap<String, String>>(req)?
+            .into_iter()
+            .map(|(k, v)| (k, serde_json::Value::String(v)))
+            .collect();
+    params.extend(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k, serde_json::Value::String(v))),
+    );
+    serde_json::from_value(serde_json::Value::Object(params))
+        .map_err(|e| HttpResponse::BadRequest().body(format!("Invalid query: {}", e)))
 }
 
-#[async_trait]
-impl ImageGenerator for RealImageGenerator {
-    async fn generate_from_url(
-        &self,
-        url: String,
-        gradient_variant: overlay::GradientColorType,
-        fade: f32,
-    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        self.manager
-            .generate_from_url(url, gradient_variant, fade)
-            .await
+#[utoipa::path(
+    post,
+    path = "/image",
+    params(
+        ("gradient_variant" = GradientType, Query, description = "Gradient type, may also be sent as a form field"),
+        ("rgb" = Option<Rgb>, Query, description = "Three RGB values (0-255) for user-defined gradient: r,g,b"),
+        ("fade" = Option<Fade>, Query, description = "Fade value between 0.0 and 1.0"),
+        ("stops" = Option<Stops>, Query, description = "Color stops for the Stops gradient"),
+        ("format" = Option<OutputFormat>, Query, description = "Output format, negotiated from the Accept header when omitted"),
+        ("quality" = Option<Quality>, Query, description = "Quality between 1 and 100 for Jpeg and Avif output, defaults to 80"),
+        ("width" = Option<Dimension>, Query, description = "Output width in pixels"),
+        ("height" = Option<Dimension>, Query, description = "Output height in pixels"),
+        ("fit" = Option<FitType>, Query, description = "How the source is fitted when both width and height are given"),
+        ("shape" = Option<ShapeType>, Query, description = "Gradient shape, defaults to Vertical")
+    ),
+    request_body(
+        description = "The raw image, or a multipart form with the image as a file part and the overlay parameters as fields",
+        content(
+            (Vec<u8> = "image/*"),
+            (Vec<u8> = "multipart/form-data")
+        )
+    ),
+    responses(
+        (status = 200, description = "Image returned in the requested or negotiated format",
+            headers(
+                ("X-Overlay-Color" = String, description = "Selected gradient color as #rrggbb"),
+                ("X-Source-Width" = u32, description = "Width of the uploaded image before resizing"),
+                ("X-Source-Height" = u32, description = "Height of the uploaded image before resizing")
+            ),
+            content(
+                (Vec<u8> = "image/png"),
+                (Vec<u8> = "image/jpeg"),
+                (Vec<u8> = "image/webp"),
+                (Vec<u8> = "image/avif")
+            )
+        ),
+        (status = 400, description = "Invalid parameters or form without a file part", body = ErrorResponse),
+        (status = 413, description = "Upload is larger than the allowed size or dimensions", body = ErrorResponse),
+        (status = 415, description = "Body is neither an image nor a multipart form", body = ErrorResponse),
+        (status = 422, description = "Upload is not a supported image", body = ErrorResponse),
+        (status = 500, description = "Image encoding failed", body = ErrorResponse)
+    )
+)]
+
+/// Create the overlay for an uploaded image, the pipeline is the same as for GET /image
+/// without fetching. Uploads are not cached
+pub async fn image_upload_handler(
+    req: actix_web::HttpRequest,
+    payload: web::Payload,
+    generator: web::Data<dyn ImageGenerator>,
+    defaults: web::Data<config::GradientDefaults>,
+) -> HttpResponse {
+    let (image, fields) = match read_upload(&req, payload).await {
+        Ok(upload) => upload,
+        Err(resp) => return resp,
+    };
+    metrics::count_bytes_in(metrics::Source::Upload, image.len());
+    let params = match parse_upload_params(&req, fields) {
+        Ok(params) => params.with_defaults(&defaults),
+        Err(resp) => return resp,
+    };
+    req.extensions_mut().insert(params.gradient_variant.clone());
+    let mut options = match params.overlay_options() {
+        Ok(options) => options,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let (format, quality, negotiated) = params.output(&req);
+    options.animate = format.animated();
+    let generated = match generator.generate_from_bytes(image, options).await {
+        Ok(generated) => generated,
+        Err(e) => {
+            log::warn!("image generation failed: {}", e);
+            return overlay_error_response(&e);
+        }
+    };
+
+    match encode_generated(&generated, format, quality, params.background()) {
+        Ok(data) => {
+            let rendered = cache::CachedImage {
+                body: data.into(),
+                content_type: format.content_type(),
+                info: generated.info,
+            };
+            image_response(&rendered, negotiated, None)
+        }
+        Err(e) => overlay_error_response(&e),
     }
 }
 
 #[utoipa::path(
     get,
-    path = "/image",
+    path = "/image/info",
     params(
         ("url" = String, Query, description = "Image URL"),
         ("gradient_variant" = GradientType, Query, description = "Gradient type"),
         ("rgb" = Option<Rgb>, Query, description = "Three RGB values (0-255) for user-defined gradient: r,g,b"),
-        ("fade" = Option<Fade>, Query, description = "Fade value between 0.0 and 1.0")
+        ("stops" = Option<Stops>, Query, description = "Color stops for the Stops gradient"),
+        ("width" = Option<Dimension>, Query, description = "Output width in pixels"),
+        ("height" = Option<Dimension>, Query, description = "Output height in pixels"),
+        ("fit" = Option<FitType>, Query, description = "How the source is fitted when both width and height are given, defaults to Cover")
     ),
     responses(
-        (status = 200, description = "PNG image returned"),
-        (status = 400, description = "Invalid query parameters"),
-        (status = 500, description = "Image generation failed")
+        (status = 200, description = "Gradient color and dimensions the image would be generated with", body = ImageInfoResponse),
+        (status = 400, description = "Invalid query parameters or image url", body = ErrorResponse),
+        (status = 403, description = "Image url refused by the fetch policy", body = ErrorResponse),
+        (status = 404, description = "Upstream image not found", body = ErrorResponse),
+        (status = 413, description = "Upstream image is larger than the allowed size or dimensions", body = ErrorResponse),
+        (status = 422, description = "Upstream content is not a supported image", body = ErrorResponse),
+        (status = 502, description = "Upstream image could not be fetched or redirected too often", body = ErrorResponse),
+        (status = 504, description = "Upstream connection or image fetch timed out", body = ErrorResponse)
     )
 )]
 
-pub async fn image_handler(
+pub async fn image_info_handler(
     req: actix_web::HttpRequest,
     generator: web::Data<dyn ImageGenerator>,
+    defaults: web::Data<config::GradientDefaults>,
 ) -> HttpResponse {
-    let query_string = req.query_string();
-    let parsed_query = web::Query::<ImageQuery>::from_query(query_string);
-
-    let query = match parsed_query {
-        Ok(q) => q.into_inner(),
-        Err(e) => {
-            let msg = match &e {
-                QueryPayloadError::Deserialize(inner) => inner.to_string(),
-                _ => e.to_string(),
-            };
-            return HttpResponse::BadRequest().body(format!("Invalid query: {}", msg));
-        }
+    let query: ImageQuery = match parse_query(&req) {
+        Ok(q) => q,
+        Err(resp) => return resp,
     };
-    let gradient_variant = match query.gradient_variant {
-        GradientType::Dominant => overlay::GradientColorType::Dominant,
-        GradientType::DominantBottom => overlay::GradientColorType::DominantBottom,
-        GradientType::UserDefined => {
-            if let Some(rgb) = query.rgb {
-                let (r, g, b) = rgb.to_tuple().unwrap();
-                overlay::GradientColorType::UserSelected(r, g, b)
-            } else {
-                return HttpResponse::BadRequest()
-                    .body("Missing mandatory rgb values for user defined gradient");
-            }
-        }
+    req.extensions_mut()
+        .insert(query.params.gradient_variant.clone());
+    let options = match query.params.with_defaults(&defaults).overlay_options() {
+        Ok(options) => options,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
     };
-    let fade_value = query.fade.unwrap_or(Fade(1.0)).0;
-    let img = generator
-        .generate_from_url(query.url, gradient_variant, fade_value)
-        .await;
-
-    // Encode the image to PNG
-    let mut buf = Cursor::new(Vec::new());
-    match img.write_to(&mut buf, image::ImageFormat::Png) {
-        Ok(_) => {
-            let png_data = buf.into_inner();
-            HttpResponse::Ok().content_type("image/png").body(png_data)
+    match generator.info_from_url(query.url, options).await {
+        Ok(info) => HttpResponse::Ok().json(ImageInfoResponse::from(&info)),
+        Err(e) => {
+            log::warn!("image info failed: {}", e);
+            overlay_error_response(&e)
         }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/palette",
+    params(
+        ("url" = String, Query, description = "Image URL"),
+        ("k" = Option<ClusterCount>, Query, description = "Number of colors between 1 and 16, defaults to 5")
+    ),
+    responses(
+        (status = 200, description = "Palette of the image, largest share first", body = PaletteResponse),
+        (status = 400, description = "Invalid query parameters or image url", body = ErrorResponse),
+        (status = 403, description = "Image url refused by the fetch policy", body = ErrorResponse),
+        (status = 404, description = "Upstream image not found", body = ErrorResponse),
+        (status = 413, description = "Upstream image is larger than the allowed size or dimensions", body = ErrorResponse),
+        (status = 422, description = "Upstream content is not a supported image", body = ErrorResponse),
+        (status = 502, description = "Upstream image could not be fetched or redirected too often", body = ErrorResponse),
+        (status = 504, description = "Upstream connection or image fetch timed out", body = ErrorResponse)
+    )
+)]
+
+pub async fn palette_handler(
+    req: actix_web::HttpRequest,
+    generator: web::Data<dyn ImageGenerator>,
+) -> HttpResponse {
+    let query: PaletteQuery = match parse_query(&req) {
+        Ok(q) => q,
+        Err(resp) => return resp,
+    };
+    let k = query.k.unwrap_or(ClusterCount(5)).0;
+    match generator.palette_from_url(query.url, k).await {
+        Ok(palette) => HttpResponse::Ok().json(PaletteResponse {
+            colors: palette.iter().map(PaletteColorResponse::from).collect(),
+        }),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Failed to encode image: {}", e))
+            log::warn!("palette extraction failed: {}", e);
+            overlay_error_response(&e)
         }
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Request counts, phase latencies, bytes in and out and cache hit ratios in the Prometheus text format", body = String, content_type = "text/plain")
+    )
+)]
+
+pub async fn metrics_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(metrics::gather())
+}
+
+/// Count the finished request by its route, status and the gradient variant the handler stored
+/// in the request extensions, and the bytes of response bodies with a known size
+async fn record_metrics(
+    req: ServiceRequest,
+    next: middleware::Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let res = next.call(req).await?;
+    let endpoint = res
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let variant = res
+        .request()
+        .extensions()
+        .get::<GradientType>()
+        .map_or_else(|| "none".to_string(), |v| format!("{:?}", v));
+    metrics::count_request(&endpoint, res.status().as_u16(), &variant);
+    if let BodySize::Sized(size) = res.response().body().size() {
+        metrics::count_bytes_out(size);
+    }
+    Ok(res)
+}
+
 #[derive(OpenApi)]
 #[openapi(
-    paths(image_handler),
-    components(schemas(ImageQuery, GradientType, Rgb, Fade))
+    paths(
+        image_handler,
+        image_upload_handler,
+        render_handler,
+        image_info_handler,
+        palette_handler,
+        metrics_handler
+    ),
+    components(schemas(
+        ImageQuery,
+        OverlayParams,
+        RenderRequest,
+        RenderSource,
+        RenderGradient,
+        RenderStop,
+        RenderOutput,
+        RenderWatermark,
+        RenderText,
+        BlendModeType,
+        BlendSpaceType,
+        RenderBox,
+        TextAlignType,
+        VerticalAlignType,
+        FontSize,
+        MaxLines,
+        WatermarkPositionType,
+        Margin,
+        Scale,
+        Opacity,
+        GradientType,
+        Rgb,
+        Fade,
+        Stops,
+        OutputFormat,
+        Quality,
+        FitType,
+        Dimension,
+        Midpoint,
+        Exponent,
+        Strength,
+        ShapeType,
+        Angle,
+        Position,
+        Radius,
+        ClusterCount,
+        PaletteResponse,
+        PaletteColorResponse,
+        ImageInfoResponse,
+        ErrorResponse
+    ))
 )]
 pub struct ApiDoc;
 
+/// Load the fonts of the font directory, without a directory text layers have no fonts
+fn load_fonts(dir: Option<&Path>) -> std::io::Result<text::FontLibrary> {
+    let Some(dir) = dir else {
+        return Ok(text::FontLibrary::default());
+    };
+    let fonts = text::FontLibrary::load(dir).map_err(|e| load_error("fonts", dir, e))?;
+    log::info!("loaded {} fonts from {}", fonts.len(), dir.display());
+    Ok(fonts)
+}
+
+/// Load the images of the assets directory, without a directory watermarks need a url
+fn load_assets(dir: Option<&Path>) -> std::io::Result<assets::AssetLibrary> {
+    let Some(dir) = dir else {
+        return Ok(assets::AssetLibrary::default());
+    };
+    let assets = assets::AssetLibrary::load(dir).map_err(|e| load_error("assets", dir, e))?;
+    log::info!("loaded {} assets from {}", assets.len(), dir.display());
+    Ok(assets)
+}
+
+fn load_error(what: &str, dir: &Path, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("failed to load {} from {}: {}", what, dir.display(), e),
+    )
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    let manager = overlay::Manager::build();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    env_logger::init_from_env(
+        env_logger::Env::new().default_filter_or(config.server.log_filter.as_str()),
+    );
+    let mut manager = overlay::Manager::build(config.fetch_policy(), config.fetch_limits())
+        .with_decode_limits(config.decode_limits())
+        .with_fonts(Arc::new(load_fonts(config.resources.font_dir.as_deref())?))
+        .with_assets(Arc::new(load_assets(
+            config.resources.assets_dir.as_deref(),
+        )?));
+    let mut render_cache = cache::RenderCache::new(
+        config.cache.max_bytes,
+        Duration::from_secs(config.cache.ttl_secs),
+    );
+    if let Some(dir) = &config.cache.disk_dir {
+        log::info!(
+            "using disk cache at {} ({} bytes)",
+            dir.display(),
+            config.cache.disk_max_bytes
+        );
+        let disk_cache = Arc::new(
+            cache::DiskCache::open(dir, config.cache.disk_max_bytes)
+                .map_err(|e| load_error("disk cache", dir, e))?
+                .with_ttl(Duration::from_secs(config.cache.disk_ttl_secs)),
+        );
+        manager = manager.with_disk_cache(disk_cache.clone());
+        render_cache = render_cache.with_disk(disk_cache);
+    }
     let generator: Arc<dyn ImageGenerator> = Arc::new(RealImageGenerator { manager });
+    let render_cache = web::Data::new(render_cache);
+    let gradient_defaults = web::Data::new(config.gradient.clone());
 
-    log::info!("starting HTTP server at http://localhost:8080");
+    log::info!(
+        "starting HTTP server at http://{}:{}",
+        config.server.host,
+        config.server.port
+    );
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             // enable logger
             .wrap(middleware::Logger::default())
+            .wrap(middleware::from_fn(record_metrics))
             .app_data(web::Data::from(generator.clone()))
-            .service(web::resource("/image").route(web::get().to(image_handler)))
+            .app_data(render_cache.clone())
+            .app_data(gradient_defaults.clone())
+            .service(
+                web::resource("/image")
+                    .route(web::get().to(image_handler))
+                    .route(web::post().to(image_upload_handler)),
+            )
+            .service(web::resource("/render").route(web::post().to(render_handler)))
+            .service(web::resource("/image/info").route(web::get().to(image_info_handler)))
+            .service(web::resource("/palette").route(web::get().to(palette_handler)))
+            .service(web::resource("/metrics").route(web::get().to(metrics_handler)))
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-doc/openapi.json", ApiDoc::openapi()),
@@ -219,17 +2112,23 @@ async fn main() -> std::io::Result<()> {
                 "/api-doc/openapi.json",
                 web::get().to(|| async { web::Json(ApiDoc::openapi()) }),
             )
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    });
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    server
+        .bind((config.server.host.as_str(), config.server.port))?
+        .run()
+        .await
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use actix_web::body::to_bytes;
+    use actix_web::dev::ServiceResponse;
     use actix_web::test::TestRequest;
+    use image::{ImageBuffer, Rgba};
     use std::sync::Arc;
 
     pub struct MockImageGenerator;
@@ -239,12 +2138,140 @@ mod test {
         async fn generate_from_url(
             &self,
             _url: String,
-            _gradient_variant: overlay::GradientColorType,
-            _fade: f32,
-        ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-            ImageBuffer::from_pixel(1, 1, Rgba([255, 0, 0, 255]))
+            options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            Ok(overlay::GeneratedImage {
+                image: ImageBuffer::from_pixel(1, 1, Rgba([255, 0, 0, 255])),
+                animation: None,
+                info: self.info_from_url(String::new(), options).await?,
+                metadata: metadata::ImageMetadata::default(),
+            })
+        }
+
+        async fn generate_from_bytes(
+            &self,
+            _image: Vec<u8>,
+            options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            self.generate_from_url(String::new(), options).await
+        }
+
+        async fn info_from_url(
+            &self,
+            _url: String,
+            _options: overlay::OverlayOptions,
+        ) -> Result<overlay::OverlayInfo, overlay::OverlayError> {
+            Ok(overlay::OverlayInfo {
+                color: palette::Srgb::new(255, 0, 0),
+                source_width: 2,
+                source_height: 3,
+                width: 1,
+                height: 1,
+            })
+        }
+
+        async fn palette_from_url(
+            &self,
+            _url: String,
+            _k: usize,
+        ) -> Result<Vec<overlay::PaletteColor>, overlay::OverlayError> {
+            let red: palette::Srgb<f32> = palette::Srgb::new(1.0, 0.0, 0.0);
+            Ok(vec![overlay::PaletteColor {
+                color: palette::Srgb::new(255, 0, 0),
+                lab: palette::IntoColor::into_color(red),
+                share: 1.0,
+            }])
+        }
+    }
+
+    pub struct FailingImageGenerator(reqwest::StatusCode);
+
+    #[async_trait]
+    impl ImageGenerator for FailingImageGenerator {
+        async fn generate_from_url(
+            &self,
+            _url: String,
+            _options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            Err(overlay::OverlayError::UpstreamStatus(self.0))
+        }
+
+        async fn generate_from_bytes(
+            &self,
+            _image: Vec<u8>,
+            _options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            Err(overlay::OverlayError::UpstreamStatus(self.0))
+        }
+
+        async fn info_from_url(
+            &self,
+            _url: String,
+            _options: overlay::OverlayOptions,
+        ) -> Result<overlay::OverlayInfo, overlay::OverlayError> {
+            Err(overlay::OverlayError::UpstreamStatus(self.0))
+        }
+
+        async fn palette_from_url(
+            &self,
+            _url: String,
+            _k: usize,
+        ) -> Result<Vec<overlay::PaletteColor>, overlay::OverlayError> {
+            Err(overlay::OverlayError::UpstreamStatus(self.0))
+        }
+    }
+    fn gradient_defaults() -> web::Data<config::GradientDefaults> {
+        web::Data::new(config::GradientDefaults::default())
+    }
+
+    fn render_cache() -> web::Data<cache::RenderCache> {
+        web::Data::new(cache::RenderCache::new(
+            1024 * 1024,
+            Duration::from_secs(60),
+        ))
+    }
+
+    /// Counts the generated images to see when the cache was used
+    #[derive(Default)]
+    pub struct CountingImageGenerator(std::sync::atomic::AtomicUsize);
+
+    #[async_trait]
+    impl ImageGenerator for CountingImageGenerator {
+        async fn generate_from_url(
+            &self,
+            url: String,
+            options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockImageGenerator.generate_from_url(url, options).await
+        }
+
+        async fn generate_from_bytes(
+            &self,
+            image: Vec<u8>,
+            options: overlay::OverlayOptions,
+        ) -> Result<overlay::GeneratedImage, overlay::OverlayError> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            MockImageGenerator.generate_from_bytes(image, options).await
+        }
+
+        async fn info_from_url(
+            &self,
+            url: String,
+            options: overlay::OverlayOptions,
+        ) -> Result<overlay::OverlayInfo, overlay::OverlayError> {
+            MockImageGenerator.info_from_url(url, options).await
+        }
+
+        async fn palette_from_url(
+            &self,
+            url: String,
+            k: usize,
+        ) -> Result<Vec<overlay::PaletteColor>, overlay::OverlayError> {
+            MockImageGenerator.palette_from_url(url, k).await
         }
     }
+
     #[test]
     fn test_fade_from_str_valid() {
         assert_eq!(Fade::from_str("0.5").unwrap(), Fade(0.5));
@@ -253,35 +2280,193 @@ mod test {
     }
 
     #[test]
-    fn test_fade_from_str_invalid() {
-        assert!(Fade::from_str("abc").is_err());
-        assert!(Fade::from_str("-0.1").is_err());
-        assert!(Fade::from_str("1.1").is_err());
+    fn test_fade_from_str_invalid() {
+        assert!(Fade::from_str("abc").is_err());
+        assert!(Fade::from_str("-0.1").is_err());
+        assert!(Fade::from_str("1.1").is_err());
+    }
+
+    #[test]
+    fn test_fade_display() {
+        let f = Fade(0.12345);
+        assert_eq!(format!("{}", f), "0.12");
+    }
+
+    #[test]
+    fn test_rgb_from_str_valid() {
+        assert_eq!(Rgb::from_str("255,0,128").unwrap(), Rgb("255,0,128".into()));
+        assert_eq!(
+            Rgb::from_str("  10 , 20 , 30 ")
+                .unwrap()
+                .to_tuple()
+                .unwrap(),
+            (10, 20, 30)
+        );
+    }
+
+    #[test]
+    fn test_rgb_from_str_invalid() {
+        assert!(Rgb::from_str("255,0").is_err());
+        assert!(Rgb::from_str("255,0,abc").is_err());
+        assert!(Rgb::from_str("255,0,256").is_err()); // 256 is out of u8 range
+    }
+
+    #[test]
+    fn test_quality_from_str() {
+        assert_eq!(Quality::from_str("75").unwrap(), Quality(75));
+        assert!(Quality::from_str("0").is_err());
+        assert!(Quality::from_str("101").is_err());
+        assert!(Quality::from_str("high").is_err());
+    }
+
+    #[test]
+    fn test_image_query_resize() {
+        let query = web::Query::<ImageQuery>::from_query(
+            "url=https://example.com/image.jpg&gradient_variant=Dominant&width=1200&height=630&fit=Contain",
+        )
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            query.params.resize(),
+            Some(overlay::Resize {
+                width: Some(1200),
+                height: Some(630),
+                fit: overlay::Fit::Contain,
+            })
+        );
+
+        let query = web::Query::<ImageQuery>::from_query(
+            "url=https://example.com/image.jpg&gradient_variant=Dominant",
+        )
+        .unwrap()
+        .into_inner();
+        assert_eq!(query.params.resize(), None);
+
+        assert!(
+            web::Query::<ImageQuery>::from_query(
+                "url=https://example.com/image.jpg&gradient_variant=Dominant&width=0",
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_image_query_gradient_spec() {
+        let query = web::Query::<ImageQuery>::from_query(
+            "url=https://example.com/image.jpg&gradient_variant=Dominant&midpoint=0.6&exponent=1.5&top_strength=0.3",
+        )
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            query.params.gradient_spec(),
+            overlay::GradientSpec {
+                shape: overlay::GradientShape::Vertical,
+                midpoint: 0.6,
+                exponent: 1.5,
+                top_strength: 0.3,
+                bottom_strength: 1.0,
+            }
+        );
+
+        for invalid in ["midpoint=1.5", "exponent=0", "bottom_strength=-0.1"] {
+            let uri = format!(
+                "url=https://example.com/image.jpg&gradient_variant=Dominant&{}",
+                invalid
+            );
+            assert!(web::Query::<ImageQuery>::from_query(&uri).is_err());
+        }
+    }
+
+    #[test]
+    fn test_image_query_gradient_shape() {
+        let spec = |params: &str| {
+            let uri = format!(
+                "url=https://example.com/image.jpg&gradient_variant=Dominant&{}",
+                params
+            );
+            web::Query::<ImageQuery>::from_query(&uri)
+                .map(|q| q.into_inner().params.gradient_spec().shape)
+        };
+
+        assert_eq!(
+            spec("shape=Linear&angle=45").unwrap(),
+            overlay::GradientShape::Linear { angle: 45.0 }
+        );
+        assert_eq!(
+            spec("shape=Radial&cx=0.2").unwrap(),
+            overlay::GradientShape::Radial {
+                cx: 0.2,
+                cy: 0.5,
+                radius: 1.0
+            }
+        );
+        assert_eq!(
+            spec("shape=Vignette").unwrap(),
+            overlay::GradientShape::Vignette
+        );
+        assert!(spec("shape=Linear&angle=400").is_err());
+        assert!(spec("shape=Spiral").is_err());
     }
 
     #[test]
-    fn test_fade_display() {
-        let f = Fade(0.12345);
-        assert_eq!(format!("{}", f), "0.12");
+    fn test_output_format_negotiate() {
+        assert_eq!(OutputFormat::negotiate(None), OutputFormat::Png);
+        assert_eq!(OutputFormat::negotiate(Some("*/*")), OutputFormat::Png);
+        assert_eq!(
+            OutputFormat::negotiate(Some(
+                "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"
+            )),
+            OutputFormat::Avif
+        );
+        assert_eq!(
+            OutputFormat::negotiate(Some("image/avif;q=0.5, image/webp")),
+            OutputFormat::Webp
+        );
+        assert_eq!(
+            OutputFormat::negotiate(Some("image/webp;q=0, image/jpeg")),
+            OutputFormat::Jpeg
+        );
+        // lossless webp is usually larger than jpeg
+        assert_eq!(
+            OutputFormat::negotiate(Some("image/webp, image/jpeg")),
+            OutputFormat::Jpeg
+        );
     }
 
     #[test]
-    fn test_rgb_from_str_valid() {
-        assert_eq!(Rgb::from_str("255,0,128").unwrap(), Rgb("255,0,128".into()));
+    fn test_stops_from_str() {
+        let stops = Stops::from_str("0:20,20,40,0.9;0.5:0,0,0,0;1:200,30,30,0.8")
+            .unwrap()
+            .to_color_stops()
+            .unwrap();
+        assert_eq!(stops.len(), 3);
         assert_eq!(
-            Rgb::from_str("  10 , 20 , 30 ")
-                .unwrap()
-                .to_tuple()
-                .unwrap(),
-            (10, 20, 30)
+            stops[2],
+            overlay::ColorStop {
+                position: 1.0,
+                color: palette::Srgb::new(200, 30, 30),
+                alpha: 0.8,
+            }
         );
+
+        assert!(Stops::from_str("").is_err());
+        assert!(Stops::from_str("0:20,20,40").is_err());
+        assert!(Stops::from_str("1.5:20,20,40,0.5").is_err());
+        assert!(Stops::from_str("0:20,20,40,2").is_err());
+        assert!(Stops::from_str("0:256,20,40,0.5").is_err());
     }
 
-    #[test]
-    fn test_rgb_from_str_invalid() {
-        assert!(Rgb::from_str("255,0").is_err());
-        assert!(Rgb::from_str("255,0,abc").is_err());
-        assert!(Rgb::from_str("255,0,256").is_err()); // 256 is out of u8 range
+    #[actix_web::test]
+    async fn test_image_handler_stops_require_stops() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/image?url=https://example.com/image.jpg&gradient_variant=Stops")
+            .to_http_request();
+
+        let resp = image_handler(req, generator, render_cache(), gradient_defaults()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
     }
 
     #[test]
@@ -305,9 +2490,9 @@ mod test {
 
         let query: ImageQuery = serde_json::from_str(json).unwrap();
         assert_eq!(query.url, "https://example.com/image.jpg");
-        assert_eq!(query.gradient_variant, GradientType::UserDefined);
-        assert_eq!(query.rgb, Some(Rgb("255,255,255".into())));
-        assert_eq!(query.fade, Some(Fade(0.5)));
+        assert_eq!(query.params.gradient_variant, GradientType::UserDefined);
+        assert_eq!(query.params.rgb, Some(Rgb("255,255,255".into())));
+        assert_eq!(query.params.fade, Some(Fade(0.5)));
     }
 
     #[test]
@@ -318,8 +2503,8 @@ mod test {
         }"#;
 
         let query: ImageQuery = serde_json::from_str(json).unwrap();
-        assert_eq!(query.rgb, None);
-        assert_eq!(query.fade, None);
+        assert_eq!(query.params.rgb, None);
+        assert_eq!(query.params.fade, None);
     }
 
     #[actix_web::test]
@@ -331,12 +2516,801 @@ mod test {
             .uri("/image?url=https://example.com/image.jpg&gradient_variant=Dominant&fade=0.5")
             .to_http_request();
 
-        let resp = image_handler(req, generator).await;
+        let resp = image_handler(req, generator, render_cache(), gradient_defaults()).await;
 
         assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
 
+        assert_eq!(resp.headers().get("X-Overlay-Color").unwrap(), "#ff0000");
+        assert_eq!(resp.headers().get("X-Source-Width").unwrap(), "2");
+        assert_eq!(resp.headers().get("X-Source-Height").unwrap(), "3");
+
         let body_bytes = to_bytes(resp.into_body()).await.unwrap();
 
         assert!(body_bytes.starts_with(&[0x89, b'P', b'N', b'G']));
     }
+
+    #[actix_web::test]
+    async fn test_image_handler_maps_upstream_errors() {
+        for (upstream, expected) in [
+            (
+                reqwest::StatusCode::NOT_FOUND,
+                actix_web::http::StatusCode::NOT_FOUND,
+            ),
+            (
+                reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+                actix_web::http::StatusCode::BAD_GATEWAY,
+            ),
+        ] {
+            let generator: web::Data<dyn ImageGenerator> = web::Data::from(Arc::new(
+                FailingImageGenerator(upstream),
+            )
+                as Arc<dyn ImageGenerator>);
+            let req = TestRequest::get()
+                .uri("/image?url=https://example.com/image.jpg&gradient_variant=Dominant")
+                .to_http_request();
+
+            let resp = image_handler(req, generator, render_cache(), gradient_defaults()).await;
+            assert_eq!(resp.status(), expected);
+
+            let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+            let body: ErrorResponse = serde_json::from_slice(&body_bytes).unwrap();
+            assert!(body.message.contains(upstream.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_overlay_error_response_limits() {
+        use actix_web::http::StatusCode;
+        use overlay::OverlayError;
+        for (err, status) in [
+            (
+                OverlayError::ConnectTimeout(Duration::from_secs(5)),
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (
+                OverlayError::Timeout(Duration::from_secs(30)),
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (OverlayError::TooManyRedirects(5), StatusCode::BAD_GATEWAY),
+            (
+                OverlayError::BodyTooLarge(1024),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                OverlayError::UnknownFont("Missing".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                OverlayError::UnknownAsset("missing".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            assert_eq!(overlay_error_response(&err).status(), status, "{}", err);
+        }
+    }
+
+    fn real_generator() -> web::Data<dyn ImageGenerator> {
+        web::Data::from(Arc::new(RealImageGenerator {
+            manager: overlay::Manager::build(
+                policy::FetchPolicy::default(),
+                overlay::FetchLimits::default(),
+            ),
+        }) as Arc<dyn ImageGenerator>)
+    }
+
+    fn png_bytes(width: u32, height: u32, color: Rgba<u8>) -> Vec<u8> {
+        let img = ImageBuffer::from_pixel(width, height, color);
+        overlay::encode_image(
+            &img,
+            image::ImageFormat::Png,
+            80,
+            palette::Srgb::new(255, 255, 255),
+            &metadata::ImageMetadata::default(),
+        )
+        .unwrap()
+    }
+
+    /// A multipart form with the text fields followed by the file part
+    fn multipart_body(fields: &[(&str, &str)], file: Option<&[u8]>) -> (String, Vec<u8>) {
+        let boundary = "overlay-test-boundary";
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        if let Some(file) = file {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n",
+                    boundary
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(file);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        (format!("multipart/form-data; boundary={}", boundary), body)
+    }
+
+    async fn post_image(uri: &str, content_type: &str, body: Vec<u8>) -> ServiceResponse {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(real_generator())
+                .app_data(gradient_defaults())
+                .route("/image", web::post().to(image_upload_handler)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri(uri)
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        actix_web::test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn test_image_upload_handler_raw_body() {
+        let resp = post_image(
+            "/image?gradient_variant=Dominant&format=Png",
+            "image/png",
+            png_bytes(2, 3, Rgba([0, 0, 255, 255])),
+        )
+        .await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(headers.get("X-Overlay-Color").unwrap(), "#0000ff");
+        assert_eq!(headers.get("X-Source-Width").unwrap(), "2");
+        assert_eq!(headers.get("X-Source-Height").unwrap(), "3");
+        assert!(headers.get("X-Cache").is_none());
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "image/png");
+        let body = actix_web::test::read_body(resp).await;
+        let img = image::load_from_memory(&body).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 3));
+    }
+
+    #[actix_web::test]
+    async fn test_image_upload_handler_multipart() {
+        let (content_type, body) = multipart_body(
+            &[("gradient_variant", "UserDefined"), ("rgb", "10,20,30")],
+            Some(&png_bytes(4, 4, Rgba([255, 255, 255, 255]))),
+        );
+        let resp = post_image("/image?format=Jpeg&width=2", &content_type, body).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(headers.get("X-Overlay-Color").unwrap(), "#0a141e");
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        let body = actix_web::test::read_body(resp).await;
+        let img = image::load_from_memory(&body).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 2));
+    }
+
+    #[actix_web::test]
+    async fn test_image_upload_handler_preserve_alpha() {
+        let transparent = png_bytes(2, 2, Rgba([0, 0, 255, 0]));
+        let resp = post_image(
+            "/image?gradient_variant=UserDefined&rgb=255,0,0&format=Png&preserve_alpha=true",
+            "image/png",
+            transparent.clone(),
+        )
+        .await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let body = actix_web::test::read_body(resp).await;
+        let img = image::load_from_memory(&body).unwrap().into_rgba8();
+        assert!(img.pixels().all(|p| p[3] == 0));
+
+        let resp = post_image(
+            "/image?gradient_variant=UserDefined&rgb=255,0,0&format=Jpeg&preserve_alpha=true&background=0,255,0",
+            "image/png",
+            transparent,
+        )
+        .await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let body = actix_web::test::read_body(resp).await;
+        let img = image::load_from_memory(&body).unwrap().into_rgb8();
+        let p = img.get_pixel(0, 0);
+        assert!(p[0] < 10 && p[1] > 245 && p[2] < 10, "{:?}", p);
+    }
+
+    #[actix_web::test]
+    async fn test_image_upload_handler_rejects_invalid_uploads() {
+        let resp = post_image(
+            "/image?gradient_variant=Dominant",
+            "text/plain",
+            b"hi".to_vec(),
+        )
+        .await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        let (content_type, body) = multipart_body(&[("gradient_variant", "Dominant")], None);
+        let resp = post_image("/image", &content_type, body).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body.error, "missing_image");
+
+        let resp = post_image("/image", "image/png", png_bytes(1, 1, Rgba([0, 0, 0, 255]))).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let resp = post_image(
+            "/image?gradient_variant=Dominant",
+            "image/png",
+            b"not an image".to_vec(),
+        )
+        .await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_image_upload_handler_limits_whole_form() {
+        // every field is below the limit but together they are above it
+        let large = "a".repeat(MAX_UPLOAD_BYTES / 2 + 1);
+        let (content_type, body) = multipart_body(&[("a", &large), ("b", &large)], None);
+        let resp = post_image("/image", &content_type, body).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let body: ErrorResponse = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body.error, "upload_too_large");
+
+        let names: Vec<String> = (0..=MAX_UPLOAD_FIELDS).map(|i| i.to_string()).collect();
+        let fields: Vec<(&str, &str)> = names.iter().map(|n| (n.as_str(), "1")).collect();
+        let (content_type, body) = multipart_body(&fields, None);
+        let resp = post_image("/image", &content_type, body).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body.error, "invalid_upload");
+    }
+
+    #[actix_web::test]
+    async fn test_render_handler_shares_cache_with_image_handler() {
+        let counting = Arc::new(CountingImageGenerator::default());
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(counting.clone() as Arc<dyn ImageGenerator>);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(generator)
+                .app_data(render_cache())
+                .app_data(gradient_defaults())
+                .route("/image", web::get().to(image_handler))
+                .route("/render", web::post().to(render_handler)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/render")
+            .set_json(serde_json::json!({
+                "source": { "url": "https://example.com/image.jpg" },
+                "gradient": {
+                    "variant": "Stops",
+                    "stops": [
+                        { "position": 0.0, "color": [20, 20, 40], "alpha": 0.9 },
+                        { "position": 1.0, "color": [200, 30, 30], "alpha": 0.5 }
+                    ],
+                    "shape": "Radial",
+                    "radius": 0.8
+                },
+                "output": { "format": "Png", "width": 600 }
+            }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Cache").unwrap(), "MISS");
+
+        let req = TestRequest::get()
+            .uri("/image?url=https://example.com/image.jpg&gradient_variant=Stops&stops=0:20,20,40,0.9;1:200,30,30,0.5&shape=Radial&radius=0.8&width=600&format=Png")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(resp.headers().get("X-Cache").unwrap(), "HIT");
+        assert_eq!(counting.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_render_text_layers() {
+        let request: RenderRequest = serde_json::from_value(serde_json::json!({
+            "source": { "url": "https://example.com/a.jpg" },
+            "gradient": { "variant": "Dominant" },
+            "text": [{
+                "content": "Hello",
+                "font": "DejaVuSansMono",
+                "size": 32,
+                "color": [10, 20, 30],
+                "align": "Center",
+                "max_lines": 2
+            }]
+        }))
+        .unwrap();
+        let query = request.into_query().unwrap();
+        let options = query.params.overlay_options().unwrap();
+        assert_eq!(options.text.len(), 1);
+        let layer = &options.text[0];
+        assert_eq!(layer.size, 32.0);
+        assert_eq!(layer.color, palette::Srgb::new(10, 20, 30));
+        assert_eq!(layer.align, text::TextAlign::Center);
+        assert_eq!(layer.vertical_align, text::VerticalAlign::Bottom);
+        assert_eq!(layer.area.width, 0.9);
+        assert_eq!(layer.max_lines, Some(2));
+
+        let mut plain = query.clone();
+        plain.params.text.clear();
+        let key = plain.cache_key(OutputFormat::Png, 80);
+        assert!(!key.contains("text"));
+        assert_ne!(query.cache_key(OutputFormat::Png, 80), key);
+    }
+
+    #[actix_web::test]
+    async fn test_render_handler_validation_paths() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(
+                    Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>
+                ))
+                .app_data(render_cache())
+                .app_data(gradient_defaults())
+                .route("/render", web::post().to(render_handler)),
+        )
+        .await;
+        for (body, path) in [
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant", "fade": 1.5 }
+                }),
+                "gradient.fade",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": {
+                        "variant": "Stops",
+                        "stops": [{ "position": 0.5, "color": [0, 0, 0], "alpha": 2.0 }]
+                    }
+                }),
+                "gradient.stops[0].alpha",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "UserDefined" }
+                }),
+                "gradient.rgb",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "output": { "width": 0 }
+                }),
+                "output.width",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "link": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" }
+                }),
+                "source.link",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "text": [{ "content": "Hi", "font": "Sans", "box": { "x": 0.0, "y": 0.0, "width": 2.0, "height": 1.0 } }]
+                }),
+                "text[0].box.width",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "text": [{ "content": "Hi", "font": "Sans", "size": 0 }]
+                }),
+                "text[0].size",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "text": [{ "content": "x".repeat(MAX_TEXT_CHARS + 1), "font": "Sans" }]
+                }),
+                "text[0].content",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "text": vec![serde_json::json!({ "content": "Hi", "font": "Sans" }); MAX_TEXT_LAYERS + 1]
+                }),
+                "text",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "watermark": { "position": "TopLeft" }
+                }),
+                "watermark",
+            ),
+            (
+                serde_json::json!({
+                    "source": { "url": "https://example.com/a.jpg" },
+                    "gradient": { "variant": "Dominant" },
+                    "watermark": { "asset": "logo", "opacity": 1.5 }
+                }),
+                "watermark.opacity",
+            ),
+        ] {
+            let req = TestRequest::post()
+                .uri("/render")
+                .set_json(body)
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let error: ErrorResponse = actix_web::test::read_body_json(resp).await;
+            assert_eq!(error.error, "invalid_request");
+            assert_eq!(error.path.as_deref(), Some(path), "{}", error.message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_image_handler_forbidden_url() {
+        let generator = real_generator();
+        for url in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8080/image",
+            "file:///etc/passwd",
+        ] {
+            let req = TestRequest::get()
+                .uri(&format!("/image?url={}&gradient_variant=Dominant", url))
+                .to_http_request();
+
+            let resp =
+                image_handler(req, generator.clone(), render_cache(), gradient_defaults()).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::FORBIDDEN);
+
+            let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+            let body: ErrorResponse = serde_json::from_slice(&body_bytes).unwrap();
+            assert_eq!(body.error, "forbidden_url");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_image_handler_explicit_format() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/image?url=https://example.com/image.jpg&gradient_variant=Dominant&format=Jpeg&quality=60")
+            .insert_header((header::ACCEPT, "image/webp"))
+            .to_http_request();
+
+        let resp = image_handler(req, generator, render_cache(), gradient_defaults()).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/jpeg"
+        );
+        assert!(resp.headers().get(header::VARY).is_none());
+        let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+        assert!(body_bytes.starts_with(&[0xFF, 0xD8, 0xFF]));
+    }
+
+    #[actix_web::test]
+    async fn test_image_handler_negotiates_format() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/image?url=https://example.com/image.jpg&gradient_variant=Dominant")
+            .insert_header((header::ACCEPT, "image/webp,*/*;q=0.8"))
+            .to_http_request();
+
+        let resp = image_handler(req, generator, render_cache(), gradient_defaults()).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/webp"
+        );
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    }
+
+    #[actix_web::test]
+    async fn test_palette_handler_with_injected_mock() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/palette?url=https://example.com/image.jpg&k=3")
+            .to_http_request();
+
+        let resp = palette_handler(req, generator).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+        let body: PaletteResponse = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(body.colors.len(), 1);
+        assert_eq!(body.colors[0].hex, "#ff0000");
+        assert_eq!(body.colors[0].rgb, [255, 0, 0]);
+        assert_eq!(body.colors[0].share, 1.0);
+    }
+
+    #[actix_web::test]
+    async fn test_palette_handler_invalid_k() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/palette?url=https://example.com/image.jpg&k=0")
+            .to_http_request();
+
+        let resp = palette_handler(req, generator).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_image_info_handler_with_injected_mock() {
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(Arc::new(MockImageGenerator) as Arc<dyn ImageGenerator>);
+
+        let req = TestRequest::get()
+            .uri("/image/info?url=https://example.com/image.jpg&gradient_variant=Dominant")
+            .to_http_request();
+
+        let resp = image_info_handler(req, generator, gradient_defaults()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+        let body: ImageInfoResponse = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(
+            body,
+            ImageInfoResponse {
+                color: "#ff0000".into(),
+                rgb: [255, 0, 0],
+                source_width: 2,
+                source_height: 3,
+                width: 1,
+                height: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_overlay_params_with_defaults() {
+        let params = |query: &str| {
+            web::Query::<ImageQuery>::from_query(query)
+                .unwrap()
+                .into_inner()
+                .params
+        };
+        let defaults = config::GradientDefaults {
+            fade: Some(Fade(0.5)),
+            midpoint: Some(Midpoint(0.3)),
+            ..config::GradientDefaults::default()
+        };
+
+        let filled = params("url=https://example.com/a.jpg&gradient_variant=Dominant&midpoint=0.6")
+            .with_defaults(&defaults);
+        let options = filled.overlay_options().unwrap();
+        assert_eq!(options.fade, 0.5);
+        assert_eq!(options.gradient.midpoint, 0.6);
+        assert_eq!(
+            options.gradient.exponent,
+            overlay::GradientSpec::default().exponent
+        );
+
+        // the defaults are part of the cache key
+        let key = |defaults: &config::GradientDefaults| {
+            ImageQuery {
+                url: "https://example.com/a.jpg".to_string(),
+                params: params("url=https://example.com/a.jpg&gradient_variant=Dominant")
+                    .with_defaults(defaults),
+            }
+            .cache_key(OutputFormat::Png, 80)
+        };
+        let plain = web::Query::<ImageQuery>::from_query(
+            "url=https://example.com/a.jpg&gradient_variant=Dominant",
+        )
+        .unwrap()
+        .cache_key(OutputFormat::Png, 80);
+        assert_eq!(key(&config::GradientDefaults::default()), plain);
+        assert_ne!(key(&defaults), plain);
+    }
+
+    #[test]
+    fn test_image_query_cache_key_normalized() {
+        let key = |query: &str| {
+            web::Query::<ImageQuery>::from_query(query)
+                .unwrap()
+                .into_inner()
+                .cache_key(OutputFormat::Png, 80)
+        };
+
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=UserDefined&rgb=1,2,3&fade=0.5"),
+            key("fade=0.50&rgb=1, 2, 3&gradient_variant=UserDefined&url=https://example.com/a.jpg")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&rgb=1,2,3"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&fade=1.0")
+        );
+        assert_ne!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/b.jpg&gradient_variant=Dominant")
+        );
+        assert_ne!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&width=100")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_asset=logo"),
+            key(
+                "url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_asset=logo&watermark_position=BottomRight&watermark_scale=0.15"
+            )
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_opacity=0.5")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&blend_mode=Normal")
+        );
+        assert_ne!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&blend_mode=SoftLight")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&blend_space=Srgb")
+        );
+        assert_ne!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&blend_space=Linear")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&background=0,0,0"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&preserve_alpha=false")
+        );
+        assert_eq!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&preserve_metadata=false")
+        );
+        assert_ne!(
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant"),
+            key("url=https://example.com/a.jpg&gradient_variant=Dominant&preserve_metadata=true")
+        );
+    }
+
+    #[test]
+    fn test_overlay_params_watermark() {
+        let params = |query: &str| {
+            web::Query::<ImageQuery>::from_query(query)
+                .unwrap()
+                .into_inner()
+                .params
+        };
+
+        let watermark = params(
+            "url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_asset=logo&watermark_position=TopLeft&watermark_opacity=0.5",
+        )
+        .watermark()
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            watermark.source,
+            overlay::WatermarkSource::Asset("logo".into())
+        );
+        assert_eq!(watermark.position, overlay::WatermarkPosition::TopLeft);
+        assert_eq!(watermark.margin, 0.03);
+        assert_eq!(watermark.scale, 0.15);
+        assert_eq!(watermark.opacity, 0.5);
+
+        assert!(
+            params("url=https://example.com/a.jpg&gradient_variant=Dominant")
+                .watermark()
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            params(
+                "url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_asset=logo&watermark_url=https://example.com/logo.png"
+            )
+            .watermark()
+            .is_err()
+        );
+        assert!(
+            web::Query::<ImageQuery>::from_query(
+                "url=https://example.com/a.jpg&gradient_variant=Dominant&watermark_asset=logo&watermark_scale=2"
+            )
+            .is_err()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_image_handler_uses_render_cache() {
+        let counting = Arc::new(CountingImageGenerator::default());
+        let generator: web::Data<dyn ImageGenerator> =
+            web::Data::from(counting.clone() as Arc<dyn ImageGenerator>);
+        let cache = render_cache();
+
+        for (uri, expected) in [
+            (
+                "/image?url=https://example.com/image.jpg&gradient_variant=Dominant&fade=0.5",
+                "MISS",
+            ),
+            (
+                "/image?fade=0.50&gradient_variant=Dominant&url=https://example.com/image.jpg",
+                "HIT",
+            ),
+        ] {
+            let req = TestRequest::get().uri(uri).to_http_request();
+            let resp =
+                image_handler(req, generator.clone(), cache.clone(), gradient_defaults()).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+            assert_eq!(resp.headers().get("X-Cache").unwrap(), expected);
+            assert_eq!(resp.headers().get("X-Overlay-Color").unwrap(), "#ff0000");
+        }
+
+        assert_eq!(counting.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_handler() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(middleware::from_fn(record_metrics))
+                .app_data(real_generator())
+                .app_data(gradient_defaults())
+                .route("/image", web::post().to(image_upload_handler))
+                .route("/metrics", web::get().to(metrics_handler)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/image?gradient_variant=UserDefined&rgb=10,20,30&format=Png")
+            .insert_header((header::CONTENT_TYPE, "image/png"))
+            .set_payload(png_bytes(2, 3, Rgba([0, 0, 255, 255])))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            metrics::CONTENT_TYPE
+        );
+        let body = actix_web::test::read_body(resp).await;
+        let text = std::str::from_utf8(&body).unwrap();
+        for series in [
+            r#"overlay_requests_total{endpoint="/image",status="200",variant="UserDefined"}"#,
+            r#"overlay_bytes_in_total{source="upload"}"#,
+            "overlay_bytes_out_total",
+        ] {
+            assert!(text.contains(series), "{}", series);
+        }
+        for phase in ["decode", "color", "overlay", "encode"] {
+            let count = format!(
+                r#"overlay_phase_duration_seconds_count{{phase="{}"}}"#,
+                phase
+            );
+            assert!(text.contains(&count), "{}", count);
+        }
+    }
 }
@@ -0,0 +1,276 @@
+use image::{DynamicImage, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+/// Metadata of a source image that can be written to the output
+/// exif: the raw TIFF structure of the EXIF data, the orientation tag is reset to the default
+/// because the orientation is applied to the pixels on decode
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImageMetadata {
+    pub exif: Option<Vec<u8>>,
+}
+
+/// EXIF tag of the orientation
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Set the orientation tag in the first IFD to 1 (no transforms), the exif is left as is
+/// when it has no orientation or can not be parsed
+pub fn reset_orientation(exif: &mut [u8]) {
+    let big_endian = match exif.get(..4) {
+        Some([0x49, 0x49, 42, 0]) => false,
+        Some([0x4d, 0x4d, 0, 42]) => true,
+        _ => return,
+    };
+    let read_u16 = |exif: &[u8], at: usize| -> Option<u16> {
+        let bytes = exif.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let Some(ifd) = exif
+        .get(4..8)
+        .and_then(|b| b.try_into().ok())
+        .map(|b| match big_endian {
+            true => u32::from_be_bytes(b),
+            false => u32::from_le_bytes(b),
+        } as usize)
+    else {
+        return;
+    };
+    let Some(entries) = read_u16(exif, ifd) else {
+        return;
+    };
+    for i in 0..entries as usize {
+        let entry = ifd + 2 + i * 12;
+        // a short value is stored in the first two bytes of the value field
+        if read_u16(exif, entry) == Some(ORIENTATION_TAG) && read_u16(exif, entry + 2) == Some(3) {
+            let value = if big_endian {
+                1u16.to_be_bytes()
+            } else {
+                1u16.to_le_bytes()
+            };
+            if let Some(field) = exif.get_mut(entry + 8..entry + 10) {
+                field.copy_from_slice(&value);
+            }
+            return;
+        }
+    }
+}
+
+/// Insert the exif as an APP1 segment after the JFIF header of an encoded jpeg
+/// Exif that does not fit in a single segment is left out
+pub fn embed_jpeg_exif(jpeg: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    const HEADER: &[u8] = b"Exif\0\0";
+    let length = 2 + HEADER.len() + exif.len();
+    if length > u16::MAX as usize || !jpeg.starts_with(&[0xff, 0xd8]) {
+        log::warn!("leaving out exif of {} bytes", exif.len());
+        return jpeg;
+    }
+    // the APP0 segment with the JFIF header has to come directly after the start of image
+    let mut at = 2;
+    if jpeg.get(2..4) == Some(&[0xff, 0xe0]) {
+        at += 2 + jpeg
+            .get(4..6)
+            .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]) as usize);
+    }
+    let mut out = Vec::with_capacity(jpeg.len() + length + 2);
+    out.extend_from_slice(&jpeg[..at]);
+    out.extend_from_slice(&[0xff, 0xe1]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    out.extend_from_slice(HEADER);
+    out.extend_from_slice(exif);
+    out.extend_from_slice(&jpeg[at..]);
+    out
+}
+
+/// Insert the exif as an eXIf chunk after the IHDR chunk of an encoded png
+pub fn embed_png_exif(png: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    // signature, then the IHDR chunk with length, type, 13 bytes of data and the crc
+    const AFTER_IHDR: usize = 8 + 4 + 4 + 13 + 4;
+    if png.get(12..16) != Some(b"IHDR") {
+        log::warn!("leaving out exif of {} bytes", exif.len());
+        return png;
+    }
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(b"eXIf");
+    crc.update(exif);
+    let mut out = Vec::with_capacity(png.len() + exif.len() + 12);
+    out.extend_from_slice(&png[..AFTER_IHDR]);
+    out.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+    out.extend_from_slice(b"eXIf");
+    out.extend_from_slice(exif);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+    out.extend_from_slice(&png[AFTER_IHDR..]);
+    out
+}
+
+/// Convert the pixels from the color space of the ICC profile to sRGB, so wide gamut sources
+/// (Display P3, Adobe RGB) keep their colors in the pipeline which works in sRGB
+/// Profiles that can not be used for RGB pixels leave the image as it is
+pub fn convert_to_srgb(img: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let profile = match ColorProfile::new_from_slice(icc) {
+        Ok(profile) if profile.color_space == DataColorSpace::Rgb => profile,
+        Ok(_) => return img,
+        Err(e) => {
+            log::warn!("ignoring icc profile: {:?}", e);
+            return img;
+        }
+    };
+    let transform = match profile.create_transform_8bit(
+        Layout::Rgba,
+        &ColorProfile::new_srgb(),
+        Layout::Rgba,
+        TransformOptions::default(),
+    ) {
+        Ok(transform) => transform,
+        Err(e) => {
+            log::warn!("ignoring icc profile: {:?}", e);
+            return img;
+        }
+    };
+    let src = img.into_rgba8();
+    let mut dst = RgbaImage::new(src.width(), src.height());
+    match transform.transform(src.as_raw(), &mut dst) {
+        Ok(()) => DynamicImage::ImageRgba8(dst),
+        Err(e) => {
+            log::warn!("ignoring icc profile: {:?}", e);
+            DynamicImage::ImageRgba8(src)
+        }
+    }
+}
+
+/// A little endian exif with only an orientation tag
+#[cfg(test)]
+pub fn exif_with_orientation(orientation: u16) -> Vec<u8> {
+    let mut exif = vec![0x49, 0x49, 42, 0];
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::PngEncoder;
+    use image::metadata::Orientation;
+    use image::{ImageDecoder, ImageEncoder, ImageReader};
+    use std::io::Cursor;
+
+    fn orientation(encoded: &[u8]) -> (Orientation, Option<Vec<u8>>) {
+        let mut decoder = ImageReader::new(Cursor::new(encoded))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        let exif = decoder.exif_metadata().unwrap();
+        (decoder.orientation().unwrap(), exif)
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/images/{}"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn icc_profile(encoded: &[u8]) -> Vec<u8> {
+        ImageReader::new(Cursor::new(encoded))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap()
+            .icc_profile()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        // the same values are more saturated in the wide gamut spaces than in sRGB
+        for (name, expected) in [
+            ("display-p3.png", [215, 93, 31, 255]),
+            ("adobe-rgb.jpg", [66, 151, 96, 255]),
+        ] {
+            let encoded = fixture(name);
+            let img = image::load_from_memory(&encoded).unwrap();
+            let converted = convert_to_srgb(img, &icc_profile(&encoded)).into_rgba8();
+            assert_eq!(converted.get_pixel(1, 1).0, expected, "{}", name);
+        }
+
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            2,
+            2,
+            image::Rgba([200, 100, 50, 128]),
+        ));
+        // an sRGB profile keeps the colors and the alpha is untouched
+        let converted = convert_to_srgb(img.clone(), &srgb).into_rgba8();
+        for (c, e) in converted.get_pixel(0, 0).0.iter().zip([200, 100, 50, 128]) {
+            assert!(c.abs_diff(e) <= 1, "{:?}", converted.get_pixel(0, 0));
+        }
+        assert_eq!(convert_to_srgb(img.clone(), b"not a profile"), img);
+    }
+
+    #[test]
+    fn test_reset_orientation() {
+        let mut exif = exif_with_orientation(6);
+        reset_orientation(&mut exif);
+        assert_eq!(exif, exif_with_orientation(1));
+
+        // the same entry in big endian
+        let mut exif = vec![
+            0x4d, 0x4d, 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1,
+        ];
+        exif.extend_from_slice(&[0, 8, 0, 0, 0, 0, 0, 0]);
+        reset_orientation(&mut exif);
+        assert_eq!(&exif[18..20], &[0, 1]);
+
+        let mut garbage = b"not exif".to_vec();
+        reset_orientation(&mut garbage);
+        assert_eq!(garbage, b"not exif");
+        let mut truncated = exif_with_orientation(6)[..12].to_vec();
+        reset_orientation(&mut truncated);
+    }
+
+    #[test]
+    fn test_embed_jpeg_exif() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg)
+            .write_image(&[255; 4 * 2 * 3], 4, 2, image::ExtendedColorType::Rgb8)
+            .unwrap();
+        let jpeg = embed_jpeg_exif(jpeg, &exif_with_orientation(6));
+        assert_eq!(&jpeg[2..4], &[0xff, 0xe0]);
+        assert_eq!(
+            orientation(&jpeg),
+            (Orientation::Rotate90, Some(exif_with_orientation(6)))
+        );
+        assert!(image::load_from_memory(&jpeg).is_ok());
+    }
+
+    #[test]
+    fn test_embed_png_exif() {
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&[255; 4 * 2 * 4], 4, 2, image::ExtendedColorType::Rgba8)
+            .unwrap();
+        let with_exif = embed_png_exif(png.clone(), &exif_with_orientation(3));
+        assert_eq!(
+            with_exif.len(),
+            png.len() + exif_with_orientation(3).len() + 12
+        );
+        assert_eq!(&with_exif[37..41], b"eXIf");
+        // the png decoder verifies the crc of every chunk
+        assert!(image::load_from_memory(&with_exif).is_ok());
+
+        assert_eq!(embed_png_exif(b"not a png".to_vec(), &[1, 2]), b"not a png");
+    }
+}
@@ -0,0 +1,283 @@
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Phases of creating an image, timed at the same points as the logged durations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Requesting the source and reading its body
+    Fetch,
+    /// Decoding, orienting, converting and resizing the source
+    Decode,
+    /// Selecting the gradient color
+    Color,
+    /// Applying the gradient, the watermark and the text layers
+    Overlay,
+    /// Encoding the output format
+    Encode,
+}
+
+impl Phase {
+    fn label(self) -> &'static str {
+        match self {
+            Phase::Fetch => "fetch",
+            Phase::Decode => "decode",
+            Phase::Color => "color",
+            Phase::Overlay => "overlay",
+            Phase::Encode => "encode",
+        }
+    }
+}
+
+/// Where the bytes of a source image came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Fetch,
+    Upload,
+}
+
+impl Source {
+    fn label(self) -> &'static str {
+        match self {
+            Source::Fetch => "fetch",
+            Source::Upload => "upload",
+        }
+    }
+}
+
+/// The caches whose lookups are counted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cache {
+    /// Rendered images, in memory or on disk
+    Render,
+    /// Fetched source images on disk
+    Source,
+}
+
+impl Cache {
+    fn label(self) -> &'static str {
+        match self {
+            Cache::Render => "render",
+            Cache::Source => "source",
+        }
+    }
+}
+
+/// Outcome of a cache lookup, a disk hit is a render cache miss in memory found on disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lookup {
+    Hit,
+    DiskHit,
+    Miss,
+}
+
+impl Lookup {
+    fn label(self) -> &'static str {
+        match self {
+            Lookup::Hit => "hit",
+            Lookup::DiskHit => "disk_hit",
+            Lookup::Miss => "miss",
+        }
+    }
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "overlay_requests_total",
+                "Finished requests by endpoint, status and gradient variant",
+            ),
+            &["endpoint", "status", "variant"],
+        )
+        .unwrap(),
+    )
+});
+
+static PHASES: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "overlay_phase_duration_seconds",
+                "Time spent in each phase of creating an image",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ]),
+            &["phase"],
+        )
+        .unwrap(),
+    )
+});
+
+static BYTES_IN: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "overlay_bytes_in_total",
+                "Bytes of source images fetched from upstream or uploaded",
+            ),
+            &["source"],
+        )
+        .unwrap(),
+    )
+});
+
+static BYTES_OUT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "overlay_bytes_out_total",
+            "Bytes of response bodies with a known size",
+        )
+        .unwrap(),
+    )
+});
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("overlay_cache_lookups_total", "Cache lookups by result"),
+            &["cache", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+static CACHE_HIT_RATIO: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "overlay_cache_hit_ratio",
+                "Share of the cache lookups that were hits since the start",
+            ),
+            &["cache"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Count a finished request, the variant is "none" for requests without a gradient
+pub fn count_request(endpoint: &str, status: u16, variant: &str) {
+    REQUESTS
+        .with_label_values(&[endpoint, &status.to_string(), variant])
+        .inc();
+}
+
+pub fn observe_phase(phase: Phase, duration: Duration) {
+    PHASES
+        .with_label_values(&[phase.label()])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn count_bytes_in(source: Source, bytes: usize) {
+    BYTES_IN
+        .with_label_values(&[source.label()])
+        .inc_by(bytes as u64);
+}
+
+pub fn count_bytes_out(bytes: u64) {
+    BYTES_OUT.inc_by(bytes);
+}
+
+pub fn count_cache_lookup(cache: Cache, lookup: Lookup) {
+    CACHE_LOOKUPS
+        .with_label_values(&[cache.label(), lookup.label()])
+        .inc();
+}
+
+/// Render all metrics in the Prometheus text format, the hit ratios are updated from the
+/// lookup counters first
+pub fn gather() -> String {
+    for cache in [Cache::Render, Cache::Source] {
+        let count = |lookup: Lookup| {
+            CACHE_LOOKUPS
+                .with_label_values(&[cache.label(), lookup.label()])
+                .get()
+        };
+        let hits = count(Lookup::Hit) + count(Lookup::DiskHit);
+        let total = hits + count(Lookup::Miss);
+        let ratio = match total {
+            0 => 0.0,
+            total => hits as f64 / total as f64,
+        };
+        CACHE_HIT_RATIO
+            .with_label_values(&[cache.label()])
+            .set(ratio);
+    }
+    // make sure every metric is listed even before it is first used
+    LazyLock::force(&REQUESTS);
+    LazyLock::force(&PHASES);
+    LazyLock::force(&BYTES_IN);
+    LazyLock::force(&BYTES_OUT);
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buf)
+        .expect("text encoding of metrics");
+    String::from_utf8(buf).expect("metrics text is utf-8")
+}
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str, series: &str) -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(series))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    #[test]
+    fn test_gather() {
+        let before = gather();
+        count_request("/image", 200, "Dominant");
+        observe_phase(Phase::Color, Duration::from_millis(3));
+        count_bytes_in(Source::Upload, 10);
+        count_bytes_out(20);
+        count_cache_lookup(Cache::Source, Lookup::Miss);
+        let after = gather();
+
+        // tests run in parallel so only the growth of the series is checked
+        for (series, growth) in [
+            (
+                r#"overlay_requests_total{endpoint="/image",status="200",variant="Dominant"}"#,
+                1.0,
+            ),
+            (
+                r#"overlay_phase_duration_seconds_bucket{phase="color",le="0.005"}"#,
+                1.0,
+            ),
+            (r#"overlay_bytes_in_total{source="upload"}"#, 10.0),
+            ("overlay_bytes_out_total", 20.0),
+            (
+                r#"overlay_cache_lookups_total{cache="source",result="miss"}"#,
+                1.0,
+            ),
+        ] {
+            assert!(
+                value(&after, series) - value(&before, series) >= growth,
+                "{}",
+                series
+            );
+        }
+        let ratio = value(&after, r#"overlay_cache_hit_ratio{cache="source"}"#);
+        assert!((0.0..1.0).contains(&ratio), "{}", ratio);
+        assert!(after.contains("# TYPE overlay_phase_duration_seconds histogram"));
+    }
+}
@@ -1,212 +1,2200 @@
+use crate::animation::{self, Animation, AnimationFrame};
+use crate::assets::AssetLibrary;
+use crate::cache::{self, DiskCache};
+use crate::metadata::{self, ImageMetadata};
+use crate::metrics::{self, Phase};
+use crate::policy::{self, FetchPolicy, PolicyResolver, PolicyViolation};
+use crate::text::{self, FontLibrary, TextLayer};
+use ab_glyph::FontArc;
 use futures_util::StreamExt;
-use image::{ImageBuffer, Rgba, RgbaImage, load_from_memory};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::gif::GifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::error::{LimitError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::{
+    DynamicImage, ImageBuffer, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageReader,
+    Rgba, RgbaImage,
+};
 use kmeans_colors::get_kmeans;
-use palette::{IntoColor, Lab, Srgb, cast::from_component_slice};
+use palette::{
+    Clamp, FromColor, IntoColor, Lab, LinSrgb, Mix, Oklab, Srgb, cast::from_component_slice,
+};
 use rayon::prelude::*;
-use reqwest;
-use std::time::Instant;
+use std::fmt;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
 /// The different options to create an gradient overly
 /// Dominant: search for the most dominat color in the whole image
 /// DominantBottom: search for the most dominat color in the bottom row of the image
 /// UserSelected: use the given rgb color as the overlay
+/// Stops: use the color and alpha of the stops placed along the gradient shape
 pub enum GradientColorType {
     Dominant,
     DominantBottom,
     UserSelected(u8, u8, u8),
+    Stops(Vec<ColorStop>),
 }
+
+/// A color with alpha (0.0 to 1.0) at a position (0.0 to 1.0) along the gradient
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorStop {
+    pub position: f32,
+    pub color: Srgb<u8>,
+    pub alpha: f32,
+}
+
+/// Information about an overlay, the selected gradient color and the dimensions of the
+/// source before and the output after resizing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverlayInfo {
+    pub color: Srgb<u8>,
+    pub source_width: u32,
+    pub source_height: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A generated overlay image together with the information about how it was created
+/// For animated sources the image is the first frame and the animation holds all frames
+/// The metadata of the source is only kept when it is asked to be preserved
+pub struct GeneratedImage {
+    pub image: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    pub animation: Option<Animation>,
+    pub info: OverlayInfo,
+    pub metadata: ImageMetadata,
+}
+
+/// A color found in an image together with the share (0.0 to 1.0) of pixels closest to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteColor {
+    pub color: Srgb<u8>,
+    pub lab: Lab,
+    pub share: f32,
+}
+
+/// How the source is fitted into the requested width and height
+/// Cover: keep the aspect ratio and crop the overflow so the whole frame is covered
+/// Contain: keep the aspect ratio and pad with black so the whole image is visible
+/// Fill: stretch to exactly the requested size
+/// Inside: keep the aspect ratio and shrink or grow to fit within the requested size, no padding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    Cover,
+    Contain,
+    Fill,
+    Inside,
+}
+
+/// Largest output width or height in pixels, also for the side calculated from the aspect ratio
+pub const MAX_DIMENSION: u32 = 8192;
+
+/// Target dimensions for the source image, when only one side is given the other
+/// is calculated from the aspect ratio of the source and capped at MAX_DIMENSION
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+}
+
+/// The shape of the gradient
+/// Vertical: band from top to bottom (the default)
+/// Horizontal: band from left to right, top settings apply to the left side
+/// Linear: band along the given angle in degrees, 0 is vertical and 90 is horizontal
+/// Radial: transparent at (cx, cy) given as fractions of the width and height, opaque at radius
+/// given as a fraction of half the image diagonal
+/// Vignette: transparent in the center, opaque in the corners
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientShape {
+    Vertical,
+    Horizontal,
+    Linear { angle: f32 },
+    Radial { cx: f32, cy: f32, radius: f32 },
+    Vignette,
+}
+
+/// The geometry of the gradient
+/// shape: the direction or form of the gradient
+/// midpoint: position along the band (0.0 start, 1.0 end) where the overlay is fully transparent
+/// exponent: falloff curve of the alpha from the midpoint towards the edges, 1.0 is linear
+/// top_strength: multiplier for the alpha above the fade threshold
+/// bottom_strength: multiplier for the alpha below the fade threshold, combined with fade.
+/// For the radial and vignette shapes it is the strength at the outer edge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradientSpec {
+    pub shape: GradientShape,
+    pub midpoint: f32,
+    pub exponent: f32,
+    pub top_strength: f32,
+    pub bottom_strength: f32,
+}
+
+impl Default for GradientSpec {
+    fn default() -> Self {
+        Self {
+            shape: GradientShape::Vertical,
+            midpoint: 0.4,
+            exponent: 2.0,
+            top_strength: 1.0,
+            bottom_strength: 1.0,
+        }
+    }
+}
+
+/// How the overlay color is combined with the image before it is mixed in with the overlay alpha
+/// Normal: the overlay color replaces the image color
+/// Multiply, Screen, Overlay, SoftLight: the separable modes applied per channel
+/// Color: hue and saturation of the overlay with the luminosity of the image
+/// Luminosity: luminosity of the overlay with the hue and saturation of the image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    /// Blend the overlay color onto the base color, channels are 0.0 to 1.0
+    fn blend(self, base: [f32; 3], overlay: [f32; 3]) -> [f32; 3] {
+        let separable = |f: fn(f32, f32) -> f32| {
+            [
+                f(base[0], overlay[0]),
+                f(base[1], overlay[1]),
+                f(base[2], overlay[2]),
+            ]
+        };
+        match self {
+            BlendMode::Normal => overlay,
+            BlendMode::Multiply => separable(|b, o| b * o),
+            BlendMode::Screen => separable(screen),
+            BlendMode::Overlay => separable(|b, o| {
+                if b <= 0.5 {
+                    2.0 * b * o
+                } else {
+                    screen(o, 2.0 * b - 1.0)
+                }
+            }),
+            BlendMode::SoftLight => separable(|b, o| {
+                if o <= 0.5 {
+                    b - (1.0 - 2.0 * o) * b * (1.0 - b)
+                } else {
+                    let d = if b <= 0.25 {
+                        ((16.0 * b - 12.0) * b + 4.0) * b
+                    } else {
+                        b.sqrt()
+                    };
+                    b + (2.0 * o - 1.0) * (d - b)
+                }
+            }),
+            BlendMode::Color => set_luminosity(overlay, luminosity(base)),
+            BlendMode::Luminosity => set_luminosity(base, luminosity(overlay)),
+        }
+    }
+}
+
+/// The color space the overlay is blended in
+/// Srgb: the encoded sRGB values are blended directly (the default)
+/// Linear: the colors are converted to linear light before blending and back afterwards, which
+/// avoids the dark bands where the gradient meets the photo
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlendSpace {
+    #[default]
+    Srgb,
+    Linear,
+}
+
+impl BlendSpace {
+    /// The channel value as 0.0 to 1.0 in this color space
+    fn decode(self, v: u8) -> f32 {
+        match self {
+            BlendSpace::Srgb => v as f32 / 255.0,
+            BlendSpace::Linear => to_linear(v),
+        }
+    }
+
+    fn encode(self, v: f32) -> u8 {
+        match self {
+            BlendSpace::Srgb => (v.clamp(0.0, 1.0) * 255.0).round() as u8,
+            BlendSpace::Linear => from_linear(v),
+        }
+    }
+}
+
+/// How the overlay is blended onto the image
+/// preserve_alpha: keep the alpha of the source and apply the overlay only within it (Porter-Duff
+/// source-atop), otherwise the output is opaque
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Blending {
+    pub mode: BlendMode,
+    pub space: BlendSpace,
+    pub preserve_alpha: bool,
+}
+
+/// Number of frames of an animation the gradient color is selected from
+const COLOR_SAMPLE_FRAMES: usize = 4;
+
+/// Number of entries in the linear to sRGB table, enough to map every sRGB value back to itself
+const LINEAR_LUT_SIZE: usize = 4096;
+
+static SRGB_TO_LINEAR: LazyLock<[f32; 256]> = LazyLock::new(|| {
+    std::array::from_fn(|v| {
+        let linear: LinSrgb<f32> = Srgb::new(v as u8, v as u8, v as u8).into_linear();
+        linear.red
+    })
+});
+
+static LINEAR_TO_SRGB: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    (0..LINEAR_LUT_SIZE)
+        .map(|i| {
+            let v = i as f32 / (LINEAR_LUT_SIZE - 1) as f32;
+            let srgb: Srgb<u8> = Srgb::from_linear(LinSrgb::new(v, v, v));
+            srgb.red
+        })
+        .collect()
+});
+
+fn to_linear(v: u8) -> f32 {
+    SRGB_TO_LINEAR[v as usize]
+}
+
+fn from_linear(v: f32) -> u8 {
+    LINEAR_TO_SRGB[(v.clamp(0.0, 1.0) * (LINEAR_LUT_SIZE - 1) as f32).round() as usize]
+}
+
+fn screen(b: f32, o: f32) -> f32 {
+    b + o - b * o
+}
+
+fn luminosity(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+/// Shift the color to the given luminosity and clip it back into range keeping the luminosity
+fn set_luminosity(c: [f32; 3], lum: f32) -> [f32; 3] {
+    let d = lum - luminosity(c);
+    let c = [c[0] + d, c[1] + d, c[2] + d];
+    let l = luminosity(c);
+    let min = c[0].min(c[1]).min(c[2]);
+    let max = c[0].max(c[1]).max(c[2]);
+    let clip = |v: f32| {
+        let v = if min < 0.0 {
+            l + (v - l) * l / (l - min)
+        } else {
+            v
+        };
+        if max > 1.0 {
+            l + (v - l) * (1.0 - l) / (max - l)
+        } else {
+            v
+        }
+    };
+    [clip(c[0]), clip(c[1]), clip(c[2])]
+}
+
+/// Where the watermark image comes from
+/// Url: fetched with the same policy and limits as the source image
+/// Asset: an image in the assets directory by file name without the extension
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatermarkSource {
+    Url(String),
+    Asset(String),
+}
+
+/// The corner or center of the image the watermark is placed in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    Center,
+    BottomLeft,
+    BottomRight,
+}
+
+/// A logo or watermark composited on top of the gradient
+/// margin: distance to the edges as a fraction of the image width, not used for Center
+/// scale: width of the watermark as a fraction of the image width, the aspect ratio is kept
+/// opacity: multiplier for the alpha of the watermark, 0.0 to 1.0
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watermark {
+    pub source: WatermarkSource,
+    pub position: WatermarkPosition,
+    pub margin: f32,
+    pub scale: f32,
+    pub opacity: f32,
+}
+
+/// The options used when creating an overlay image, the watermark is composited on the
+/// gradient and the text layers are drawn in order on top of both.
+/// animate: keep all frames of animated GIF and WebP sources, otherwise the first frame is used
+/// preserve_metadata: keep the EXIF metadata of the source for the output, it is stripped otherwise
+pub struct OverlayOptions {
+    pub gradient_variant: GradientColorType,
+    pub fade: f32,
+    pub gradient: GradientSpec,
+    pub resize: Option<Resize>,
+    pub blending: Blending,
+    pub watermark: Option<Watermark>,
+    pub text: Vec<TextLayer>,
+    pub animate: bool,
+    pub preserve_metadata: bool,
+}
+
+/// Errors that can occur while fetching an image and creating the overlay
+/// InvalidUrl: the url could not be used to build a request
+/// Forbidden: the url or an address it resolves or redirects to is refused by the fetch policy
+/// Fetch: the request to the upstream server failed
+/// ConnectTimeout: no connection to the upstream server within the connect timeout
+/// Timeout: the upstream server did not send the whole image within the timeout
+/// TooManyRedirects: the upstream server redirected more than the allowed number of times
+/// UpstreamStatus: the upstream server answered with a non 2xx status
+/// BodyTooLarge: the response body is larger than the allowed number of bytes
+/// BodyRead: reading the response body failed
+/// ImageTooLarge: the image is larger than the decode limits allow
+/// UnknownFont: a text layer uses a font that is not in the font directory
+/// UnknownAsset: the watermark uses an asset that is not in the assets directory
+/// Decode: the fetched bytes are not a supported or valid image
+/// Encode: the resulting image could not be encoded
+#[derive(Debug)]
+pub enum OverlayError {
+    InvalidUrl(String),
+    Forbidden(PolicyViolation),
+    Fetch(reqwest::Error),
+    ConnectTimeout(Duration),
+    Timeout(Duration),
+    TooManyRedirects(usize),
+    UpstreamStatus(reqwest::StatusCode),
+    BodyTooLarge(u64),
+    BodyRead(reqwest::Error),
+    ImageTooLarge(image::ImageError),
+    UnknownFont(String),
+    UnknownAsset(String),
+    Decode(image::ImageError),
+    Encode(image::ImageError),
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayError::InvalidUrl(msg) => write!(f, "Invalid image url: {}", msg),
+            OverlayError::Forbidden(violation) => write!(f, "Image url refused: {}", violation),
+            OverlayError::Fetch(e) => write!(f, "Failed to fetch image: {}", e),
+            OverlayError::ConnectTimeout(timeout) => {
+                write!(
+                    f,
+                    "Upstream server did not accept a connection within {:?}",
+                    timeout
+                )
+            }
+            OverlayError::Timeout(timeout) => {
+                write!(
+                    f,
+                    "Upstream server did not send the image within {:?}",
+                    timeout
+                )
+            }
+            OverlayError::TooManyRedirects(max) => {
+                write!(f, "Upstream server redirected more than {} times", max)
+            }
+            OverlayError::UpstreamStatus(status) => {
+                write!(f, "Upstream server responded with {}", status)
+            }
+            OverlayError::BodyTooLarge(max) => {
+                write!(f, "Image is larger than the allowed {} bytes", max)
+            }
+            OverlayError::BodyRead(e) => write!(f, "Failed to read image body: {}", e),
+            OverlayError::ImageTooLarge(e) => write!(f, "Image is too large to decode: {}", e),
+            OverlayError::UnknownFont(name) => write!(f, "Unknown font: {}", name),
+            OverlayError::UnknownAsset(name) => write!(f, "Unknown asset: {}", name),
+            OverlayError::Decode(e) => write!(f, "Failed to decode image: {}", e),
+            OverlayError::Encode(e) => write!(f, "Failed to encode image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OverlayError {}
+
+/// Limits for fetching an upstream image
+/// connect_timeout: time allowed to establish the connection
+/// timeout: time allowed for the whole request including reading the body
+/// max_body_bytes: largest accepted response body, checked while streaming
+/// max_redirects: number of redirects followed before giving up
+/// max_idle_connections: idle connections kept open per upstream host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FetchLimits {
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    pub max_body_bytes: u64,
+    pub max_redirects: usize,
+    pub max_idle_connections: usize,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_body_bytes: 32 * 1024 * 1024,
+            max_redirects: 5,
+            max_idle_connections: 8,
+        }
+    }
+}
+
+/// Limits for decoding an image so a small file declaring huge dimensions is rejected
+/// before the pixels are allocated
+/// max_width, max_height: largest accepted dimensions of the source
+/// max_alloc: largest number of bytes the decoded image may use
+/// downscale_above: sources with a longer side than this are downscaled before the overlay step
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_alloc: u64,
+    pub downscale_above: Option<u32>,
+}
+
+impl DecodeLimits {
+    /// The limits for the decoders of the image crate
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 16384,
+            max_height: 16384,
+            max_alloc: 512 * 1024 * 1024,
+            downscale_above: None,
+        }
+    }
+}
+
 pub struct Manager {
     client: reqwest::Client,
+    policy: Arc<FetchPolicy>,
+    limits: FetchLimits,
+    decode_limits: DecodeLimits,
+    fonts: Arc<FontLibrary>,
+    assets: Arc<AssetLibrary>,
+    disk_cache: Option<Arc<DiskCache>>,
 }
 
 impl Manager {
-    pub fn build() -> Self {
+    /// The policy is checked for the requested url and every redirect, host names are
+    /// resolved by the client so the addresses are checked before connecting
+    pub fn build(policy: FetchPolicy, limits: FetchLimits) -> Self {
+        let policy = Arc::new(policy);
+        let redirect_policy = policy.clone();
         let client = reqwest::Client::builder()
-            .pool_max_idle_per_host(8)
+            .pool_max_idle_per_host(limits.max_idle_connections)
+            .connect_timeout(limits.connect_timeout)
+            .timeout(limits.timeout)
+            .dns_resolver(Arc::new(PolicyResolver::new(policy.clone())))
+            // a proxy from HTTP_PROXY/HTTPS_PROXY would resolve the upstream hosts itself, so
+            // names pointing at internal addresses would pass the policy
+            .no_proxy()
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > limits.max_redirects {
+                    attempt.error("too many redirects")
+                } else if let Err(violation) = redirect_policy.check_url(attempt.url()) {
+                    attempt.error(violation)
+                } else {
+                    attempt.follow()
+                }
+            }))
             .build()
             .expect("Failed to build client");
-        Self { client }
+        Self {
+            client,
+            policy,
+            limits,
+            decode_limits: DecodeLimits::default(),
+            fonts: Arc::new(FontLibrary::default()),
+            assets: Arc::new(AssetLibrary::default()),
+            disk_cache: None,
+        }
+    }
+
+    /// The fonts text layers can use
+    pub fn with_fonts(mut self, fonts: Arc<FontLibrary>) -> Self {
+        self.fonts = fonts;
+        self
+    }
+
+    /// The images watermarks can use by name
+    pub fn with_assets(mut self, assets: Arc<AssetLibrary>) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    pub fn with_decode_limits(mut self, decode_limits: DecodeLimits) -> Self {
+        self.decode_limits = decode_limits;
+        self
+    }
+
+    /// Keep fetched source images in the disk cache so they are only downloaded once
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
     }
     ///
     /// Fetch an imge from the given url and create a new image with the specified overlay
-    /// and save the image to disk
     /// The overlay is constucted to got from the botom to 60% of the image hight where it will be no
     /// overlay and up to the top increasing the overlay color
+    /// When a resize is given the image is resized before the gradient color is selected
     pub async fn generate_from_url(
         &self,
         url: String,
-        gradient_variant: GradientColorType,
-        fade: f32,
-    ) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        options: OverlayOptions,
+    ) -> Result<GeneratedImage, OverlayError> {
+        let buffer = self.fetch(url).await?;
+        self.generate_from_bytes(&buffer, options).await
+    }
+
+    /// Create the overlay image from already fetched or uploaded image bytes
+    pub async fn generate_from_bytes(
+        &self,
+        buffer: &[u8],
+        options: OverlayOptions,
+    ) -> Result<GeneratedImage, OverlayError> {
+        // look up the fonts and the watermark first so a missing one fails before any decoding
+        let text_layers = options
+            .text
+            .iter()
+            .map(|layer| match self.fonts.get(&layer.font) {
+                Some(font) => Ok((layer, font)),
+                None => Err(OverlayError::UnknownFont(layer.font.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let watermark = match &options.watermark {
+            Some(watermark) => Some((watermark, self.watermark_image(&watermark.source).await?)),
+            None => None,
+        };
+        let watermark = watermark.as_ref().map(|(w, img)| (*w, img.as_ref()));
+        let start = Instant::now();
+        if options.animate
+            && let Some(animation) = animation::decode_animation(buffer, &self.decode_limits)?
+        {
+            let (mut animation, info) =
+                prepare_animation(animation, &options, &self.decode_limits, start)?;
+            let overlay_start = Instant::now();
+            animation.frames.par_iter_mut().for_each(|frame| {
+                let img = std::mem::take(&mut frame.image);
+                frame.image = render_frame(img, info.color, &options, watermark, &text_layers);
+            });
+            metrics::observe_phase(Phase::Overlay, overlay_start.elapsed());
+            let duration = start.elapsed();
+            log::debug!("create animation took: {:?}", duration);
+            return Ok(GeneratedImage {
+                image: animation.frames[0].image.clone(),
+                animation: Some(animation),
+                info,
+                metadata: ImageMetadata::default(),
+            });
+        }
+        let (img, info, metadata) = prepare_image(buffer, &options, &self.decode_limits)?;
+        let overlay_start = Instant::now();
+        let img = render_frame(img, info.color, &options, watermark, &text_layers);
+        metrics::observe_phase(Phase::Overlay, overlay_start.elapsed());
+        let duration = start.elapsed();
+        println!("create image took: {:?}", duration);
+        Ok(GeneratedImage {
+            image: img,
+            animation: None,
+            info,
+            metadata: match options.preserve_metadata {
+                true => metadata,
+                false => ImageMetadata::default(),
+            },
+        })
+    }
+
+    /// Fetch an image from the given url and select the gradient color the same way as
+    /// generate_from_url but without creating the overlay
+    pub async fn info_from_url(
+        &self,
+        url: String,
+        options: OverlayOptions,
+    ) -> Result<OverlayInfo, OverlayError> {
+        let buffer = self.fetch(url).await?;
+        let start = Instant::now();
+        let (_, info, _) = prepare_image(&buffer, &options, &self.decode_limits)?;
+        let duration = start.elapsed();
+        log::debug!("create info took: {:?}", duration);
+        Ok(info)
+    }
+
+    /// Fetch an image from the given url and find the k most common colors
+    /// sorted by the share of pixels closest to them
+    pub async fn palette_from_url(
+        &self,
+        url: String,
+        k: usize,
+    ) -> Result<Vec<PaletteColor>, OverlayError> {
+        let buffer = self.fetch(url).await?;
+        let start = Instant::now();
+        let (img, _) = decode_image(&buffer, &self.decode_limits)?;
+        let img = downscale_image(img, &self.decode_limits).into_rgb8();
+        let palette = calculate_palette(img.as_raw(), k);
+        let duration = start.elapsed();
+        log::debug!("create palette took: {:?}", duration);
+        Ok(palette)
+    }
+
+    /// The watermark image from the assets or fetched and decoded from its url
+    async fn watermark_image(
+        &self,
+        source: &WatermarkSource,
+    ) -> Result<Arc<RgbaImage>, OverlayError> {
+        match source {
+            WatermarkSource::Asset(name) => self
+                .assets
+                .get(name)
+                .ok_or_else(|| OverlayError::UnknownAsset(name.clone())),
+            WatermarkSource::Url(url) => {
+                let buffer = self.fetch(url.clone()).await?;
+                let (img, _) = decode_image(&buffer, &self.decode_limits)?;
+                Ok(Arc::new(img.into_rgba8()))
+            }
+        }
+    }
+
+    /// Map an error from sending the request to the most specific overlay error
+    fn request_error(&self, e: reqwest::Error) -> OverlayError {
+        if let Some(violation) = policy::find_violation(&e) {
+            OverlayError::Forbidden(violation)
+        } else if e.is_builder() {
+            OverlayError::InvalidUrl(e.to_string())
+        } else if e.is_redirect() {
+            OverlayError::TooManyRedirects(self.limits.max_redirects)
+        } else if e.is_timeout() && e.is_connect() {
+            OverlayError::ConnectTimeout(self.limits.connect_timeout)
+        } else if e.is_timeout() {
+            OverlayError::Timeout(self.limits.timeout)
+        } else {
+            OverlayError::Fetch(e)
+        }
+    }
+
+    fn body_error(&self, e: reqwest::Error) -> OverlayError {
+        if e.is_timeout() {
+            OverlayError::Timeout(self.limits.timeout)
+        } else {
+            OverlayError::BodyRead(e)
+        }
+    }
+
+    /// Fetch the raw bytes of the image at the given url
+    async fn fetch(&self, url: String) -> Result<Vec<u8>, OverlayError> {
+        let parsed =
+            reqwest::Url::parse(&url).map_err(|e| OverlayError::InvalidUrl(e.to_string()))?;
+        self.policy
+            .check_url(&parsed)
+            .map_err(OverlayError::Forbidden)?;
+        if let Some(disk_cache) = &self.disk_cache
+            && let Some(buffer) = disk_cache.get(cache::SOURCES, &url).await
+        {
+            metrics::count_cache_lookup(metrics::Cache::Source, metrics::Lookup::Hit);
+            return Ok(buffer);
+        }
+        if self.disk_cache.is_some() {
+            metrics::count_cache_lookup(metrics::Cache::Source, metrics::Lookup::Miss);
+        }
         let start = Instant::now();
         let response = self
             .client
-            .get(url)
+            .get(parsed)
             .header("Accept-Encoding", "gzip, deflate")
             .send()
             .await
-            .expect("failed to fetch");
-        let duration = start.elapsed();
-        println!("Request took: {:?}", duration);
-        let start = Instant::now();
-        let content_length = response.content_length().unwrap_or(0) as usize;
+            .map_err(|e| self.request_error(e))?;
+        if !response.status().is_success() {
+            return Err(OverlayError::UpstreamStatus(response.status()));
+        }
+        let requested = start.elapsed();
+        println!("Request took: {:?}", requested);
+        let max_body_bytes = self.limits.max_body_bytes;
+        // the length is only used to fail early, the body is still counted while streaming
+        if response.content_length().unwrap_or(0) > max_body_bytes {
+            return Err(OverlayError::BodyTooLarge(max_body_bytes));
+        }
         let mut stream = response.bytes_stream();
-        let mut buffer: Vec<u8> = Vec::with_capacity(content_length);
+        let mut buffer: Vec<u8> = Vec::new();
         while let Some(chunk) = stream.next().await {
-            let chunk = chunk.expect("Failed to read chunk");
+            let chunk = chunk.map_err(|e| self.body_error(e))?;
+            if (buffer.len() + chunk.len()) as u64 > max_body_bytes {
+                return Err(OverlayError::BodyTooLarge(max_body_bytes));
+            }
             buffer.extend_from_slice(&chunk);
         }
         let duration = start.elapsed();
-        println!("get bytes took: {:?}", duration);
-        let start = Instant::now();
-        let dynamic_img = load_from_memory(&buffer).expect("Failed to load image from memory");
-        let img = dynamic_img.to_rgba8();
-        let (width, height) = img.dimensions();
-        let gradient_rgb = select_gradient_color(gradient_variant, width, height, &img);
-        let img = create_overlay_image(width, height, gradient_rgb, img, fade);
-        let duration = start.elapsed();
-        println!("create image took: {:?}", duration);
-        img
+        println!("get bytes took: {:?}", duration - requested);
+        metrics::observe_phase(Phase::Fetch, duration);
+        metrics::count_bytes_in(metrics::Source::Fetch, buffer.len());
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.put(cache::SOURCES, &url, buffer.clone()).await;
+        }
+        Ok(buffer)
+    }
+}
+
+/// Decode the image within the limits, oriented by its exif and converted to sRGB by its icc profile
+fn decode_image(
+    buffer: &[u8],
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, ImageMetadata), OverlayError> {
+    let too_large = || {
+        OverlayError::ImageTooLarge(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::InsufficientMemory,
+        )))
+    };
+    let decode_error = |e| match e {
+        ImageError::Limits(_) => OverlayError::ImageTooLarge(e),
+        e => OverlayError::Decode(e),
+    };
+    let mut reader = ImageReader::new(std::io::Cursor::new(buffer))
+        .with_guessed_format()
+        .map_err(|e| OverlayError::Decode(ImageError::IoError(e)))?;
+    reader.limits(limits.image_limits());
+    let mut decoder = reader.into_decoder().map_err(decode_error)?;
+    if decoder.total_bytes() > limits.max_alloc {
+        return Err(too_large());
+    }
+    // broken metadata is ignored, the pixels can still be used
+    let mut exif = decoder.exif_metadata().unwrap_or(None);
+    let orientation = decoder
+        .orientation()
+        .unwrap_or(image::metadata::Orientation::NoTransforms);
+    let icc = decoder.icc_profile().unwrap_or(None);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    // the rgba conversion of the pipeline needs 4 bytes per pixel on top of the decoded image
+    if img.width() as u64 * img.height() as u64 * 4 > limits.max_alloc {
+        return Err(too_large());
+    }
+    if let Some(icc) = icc {
+        img = metadata::convert_to_srgb(img, &icc);
+    }
+    img.apply_orientation(orientation);
+    if let Some(exif) = exif.as_mut() {
+        metadata::reset_orientation(exif);
+    }
+    Ok((img, ImageMetadata { exif }))
+}
+
+/// Downscale images with a longer side than downscale_above keeping the aspect ratio
+fn downscale_image(img: DynamicImage, limits: &DecodeLimits) -> DynamicImage {
+    match limits.downscale_above {
+        Some(max) if img.width().max(img.height()) > max => {
+            img.resize(max, max, FilterType::Triangle)
+        }
+        _ => img,
+    }
+}
+
+/// Decode, orient, convert to sRGB and resize the image and select the gradient color on the
+/// final frame
+fn prepare_image(
+    buffer: &[u8],
+    options: &OverlayOptions,
+    limits: &DecodeLimits,
+) -> Result<(RgbaImage, OverlayInfo, ImageMetadata), OverlayError> {
+    let start = Instant::now();
+    let (dynamic_img, metadata) = decode_image(buffer, limits)?;
+    let (source_width, source_height) = (dynamic_img.width(), dynamic_img.height());
+    let dynamic_img = downscale_image(dynamic_img, limits);
+    let dynamic_img = match options.resize {
+        Some(resize) => resize_image(dynamic_img, resize),
+        None => dynamic_img,
+    };
+    let img = dynamic_img.into_rgba8();
+    metrics::observe_phase(Phase::Decode, start.elapsed());
+    let start = Instant::now();
+    let (width, height) = img.dimensions();
+    let color = select_gradient_color(&options.gradient_variant, width, height, &img);
+    metrics::observe_phase(Phase::Color, start.elapsed());
+    let info = OverlayInfo {
+        color,
+        source_width,
+        source_height,
+        width,
+        height,
+    };
+    Ok((img, info, metadata))
+}
+
+/// Downscale and resize every frame and select the gradient color across a sample of the frames,
+/// the decode phase started with decoding the frames at decode_start
+fn prepare_animation(
+    animation: Animation,
+    options: &OverlayOptions,
+    limits: &DecodeLimits,
+    decode_start: Instant,
+) -> Result<(Animation, OverlayInfo), OverlayError> {
+    let (source_width, source_height) = animation.frames[0].image.dimensions();
+    // the decoded frames are within the limits, the resized frames have to be as well
+    if let Some(resize) = options.resize
+        && let Some((width, height)) = resize_dimensions(source_width, source_height, resize)
+        && animation.frames.len() as u64 * width as u64 * height as u64 * 4 > limits.max_alloc
+    {
+        return Err(OverlayError::ImageTooLarge(ImageError::Limits(
+            LimitError::from_kind(LimitErrorKind::InsufficientMemory),
+        )));
+    }
+    let frames: Vec<AnimationFrame> = animation
+        .frames
+        .into_par_iter()
+        .map(|frame| {
+            let img = downscale_image(DynamicImage::ImageRgba8(frame.image), limits);
+            let img = match options.resize {
+                Some(resize) => resize_image(img, resize),
+                None => img,
+            };
+            AnimationFrame {
+                image: img.into_rgba8(),
+                delay: frame.delay,
+            }
+        })
+        .collect();
+    metrics::observe_phase(Phase::Decode, decode_start.elapsed());
+    let start = Instant::now();
+    let (width, height) = frames[0].image.dimensions();
+    let step = frames.len().div_ceil(COLOR_SAMPLE_FRAMES);
+    let sampled: Vec<&RgbaImage> = frames.iter().step_by(step).map(|f| &f.image).collect();
+    let color = select_frames_gradient_color(&options.gradient_variant, width, height, &sampled);
+    metrics::observe_phase(Phase::Color, start.elapsed());
+    let info = OverlayInfo {
+        color,
+        source_width,
+        source_height,
+        width,
+        height,
+    };
+    let animation = Animation {
+        frames,
+        loop_count: animation.loop_count,
+    };
+    Ok((animation, info))
+}
+
+/// Apply the gradient overlay, the watermark and the text layers to a prepared frame
+fn render_frame(
+    img: RgbaImage,
+    color: Srgb<u8>,
+    options: &OverlayOptions,
+    watermark: Option<(&Watermark, &RgbaImage)>,
+    text_layers: &[(&TextLayer, &FontArc)],
+) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let mut img = match &options.gradient_variant {
+        GradientColorType::Stops(stops) => create_stops_overlay_image(
+            width,
+            height,
+            stops,
+            img,
+            &options.gradient,
+            options.blending,
+        ),
+        _ => create_overlay_image(
+            width,
+            height,
+            color,
+            img,
+            options.fade,
+            &options.gradient,
+            options.blending,
+        ),
+    };
+    if let Some((watermark, watermark_img)) = watermark {
+        composite_watermark(&mut img, watermark_img, watermark);
+    }
+    for (layer, font) in text_layers {
+        text::draw_text(&mut img, layer, font);
+    }
+    img
+}
+
+/// Dimensions of a source resized with the given resize, for Inside the size it fits within.
+/// None when the source keeps its size
+fn resize_dimensions(src_width: u32, src_height: u32, resize: Resize) -> Option<(u32, u32)> {
+    let (src_width, src_height) = (src_width.max(1), src_height.max(1));
+    match (resize.width, resize.height) {
+        (Some(w), Some(h)) => Some((w, h)),
+        (Some(w), None) => {
+            let h = (src_height as f64 * w as f64 / src_width as f64).round() as u32;
+            Some((w, h.clamp(1, MAX_DIMENSION)))
+        }
+        (None, Some(h)) => {
+            let w = (src_width as f64 * h as f64 / src_height as f64).round() as u32;
+            Some((w.clamp(1, MAX_DIMENSION), h))
+        }
+        (None, None) => None,
+    }
+}
+
+/// Resize the image according to the given fit using a lanczos filter
+fn resize_image(img: DynamicImage, resize: Resize) -> DynamicImage {
+    let Some((width, height)) = resize_dimensions(img.width(), img.height(), resize) else {
+        return img;
+    };
+    if resize.width.is_none() || resize.height.is_none() {
+        return img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+    match resize.fit {
+        Fit::Cover => img.resize_to_fill(width, height, FilterType::Lanczos3),
+        Fit::Fill => img.resize_exact(width, height, FilterType::Lanczos3),
+        Fit::Inside => img.resize(width, height, FilterType::Lanczos3),
+        Fit::Contain => {
+            let inner = img.resize(width, height, FilterType::Lanczos3).into_rgba8();
+            let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+            let x = (width - inner.width()) / 2;
+            let y = (height - inner.height()) / 2;
+            image::imageops::overlay(&mut canvas, &inner, x as i64, y as i64);
+            DynamicImage::ImageRgba8(canvas)
+        }
+    }
+}
+
+/// Encode the image in the given format
+/// quality is used for the lossy formats (jpeg and avif), webp is always encoded lossless and
+/// gif is quantized to a palette.
+/// Jpeg has no alpha channel so the image is flattened onto the background color before encoding
+/// The exif of the metadata is written to jpeg, png and webp, the other formats leave it out
+pub fn encode_image(
+    img: &RgbaImage,
+    format: ImageFormat,
+    quality: u8,
+    background: Srgb<u8>,
+    metadata: &ImageMetadata,
+) -> Result<Vec<u8>, OverlayError> {
+    let start = Instant::now();
+    let (width, height) = img.dimensions();
+    let mut buf = Vec::new();
+    let result =
+        match format {
+            ImageFormat::Jpeg => {
+                let background = Rgba([background.red, background.green, background.blue, 255]);
+                let rgb = image::RgbImage::from_fn(width, height, |x, y| {
+                    let p = blend_over(background, *img.get_pixel(x, y));
+                    image::Rgb([p[0], p[1], p[2]])
+                });
+                JpegEncoder::new_with_quality(&mut buf, quality).write_image(
+                    rgb.as_raw(),
+                    width,
+                    height,
+                    image::ExtendedColorType::Rgb8,
+                )
+            }
+            ImageFormat::WebP => {
+                let mut encoder = image_webp::WebPEncoder::new(&mut buf);
+                if let Some(exif) = &metadata.exif {
+                    encoder.set_exif_metadata(exif.clone());
+                }
+                encoder
+                    .encode(img.as_raw(), width, height, image_webp::ColorType::Rgba8)
+                    .map_err(|e| {
+                        ImageError::Encoding(image::error::EncodingError::new(
+                            image::error::ImageFormatHint::Exact(ImageFormat::WebP),
+                            e,
+                        ))
+                    })
+            }
+            ImageFormat::Gif => GifEncoder::new_with_speed(&mut buf, 10).encode(
+                img.as_raw(),
+                width,
+                height,
+                image::ExtendedColorType::Rgba8,
+            ),
+            ImageFormat::Avif => AvifEncoder::new_with_speed_quality(&mut buf, 8, quality)
+                .write_image(img.as_raw(), width, height, image::ExtendedColorType::Rgba8),
+            _ => PngEncoder::new(&mut buf).write_image(
+                img.as_raw(),
+                width,
+                height,
+                image::ExtendedColorType::Rgba8,
+            ),
+        };
+    result.map_err(OverlayError::Encode)?;
+    let buf = match (&metadata.exif, format) {
+        (Some(exif), ImageFormat::Jpeg) => metadata::embed_jpeg_exif(buf, exif),
+        (Some(exif), ImageFormat::Png) => metadata::embed_png_exif(buf, exif),
+        _ => buf,
+    };
+    let duration = start.elapsed();
+    log::debug!("encode image took: {:?}", duration);
+    Ok(buf)
+}
+
+fn select_gradient_color(
+    select: &GradientColorType,
+    width: u32,
+    height: u32,
+    img: &image::ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Srgb<u8> {
+    select_frames_gradient_color(select, width, height, &[img])
+}
+
+/// Select the gradient color from the pixels of all the given frames
+fn select_frames_gradient_color(
+    select: &GradientColorType,
+    width: u32,
+    height: u32,
+    frames: &[&RgbaImage],
+) -> Srgb<u8> {
+    match select {
+        GradientColorType::Dominant => {
+            let flat: Vec<u8> = frames
+                .iter()
+                .flat_map(|img| img.pixels().flat_map(|p| p.0[..3].to_vec()))
+                .collect();
+            calculate_dominant_color(&flat)
+        }
+        GradientColorType::DominantBottom => {
+            let flat: Vec<u8> = frames
+                .iter()
+                .flat_map(|img| {
+                    (0..width).flat_map(move |x| {
+                        let pixel = img.get_pixel(x, height - 1); // y = 0 for the first row
+                        pixel.0[..3].to_vec() // RGB only
+                    })
+                })
+                .collect();
+            calculate_dominant_color(&flat)
+        }
+        GradientColorType::UserSelected(r, g, b) => Srgb::<u8>::new(*r, *g, *b),
+        // the most visible stop is reported as the gradient color
+        GradientColorType::Stops(stops) => stops
+            .iter()
+            .max_by(|a, b| a.alpha.total_cmp(&b.alpha))
+            .map_or(Srgb::new(0, 0, 0), |stop| stop.color),
+    }
+}
+
+/// Where a pixel is located in the gradient shape
+/// Band: distance in pixels from the start of a band with the given length
+/// Radial: distance from the center where 1.0 is the edge of the gradient
+enum Location {
+    Band { position: f32, length: f32 },
+    Radial(f32),
+}
+
+/// Generates the overlay alpha (0.0 to 1.0) for every pixel of an image from a gradient spec
+struct AlphaField<'a> {
+    spec: &'a GradientSpec,
+    width: f32,
+    height: f32,
+    fade: f32,
+    // direction and pixel length of the band for the linear shape
+    direction: (f32, f32),
+    length: f32,
+}
+
+impl<'a> AlphaField<'a> {
+    fn new(spec: &'a GradientSpec, width: u32, height: u32, fade: f32) -> Self {
+        let (width, height) = (width as f32, height as f32);
+        let (direction, length) = match spec.shape {
+            GradientShape::Linear { angle } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                ((sin, cos), width * sin.abs() + height * cos.abs())
+            }
+            _ => ((0.0, 1.0), height),
+        };
+        Self {
+            spec,
+            width,
+            height,
+            fade,
+            direction,
+            length,
+        }
+    }
+
+    fn sample(&self, x: u32, y: u32) -> f32 {
+        match self.locate(x, y) {
+            Location::Band { position, length } => self.band(position, length),
+            Location::Radial(distance) => self.radial(distance),
+        }
+    }
+
+    /// Normalized position of the pixel in the shape, 0.0 at the start of a band or the
+    /// center of a radial gradient and 1.0 at the end or edge
+    fn position(&self, x: u32, y: u32) -> f32 {
+        match self.locate(x, y) {
+            Location::Band { position, length } => (position / length).clamp(0.0, 1.0),
+            Location::Radial(distance) => distance.min(1.0),
+        }
+    }
+
+    fn locate(&self, x: u32, y: u32) -> Location {
+        match self.spec.shape {
+            GradientShape::Vertical => Location::Band {
+                position: y as f32,
+                length: self.height,
+            },
+            GradientShape::Horizontal => Location::Band {
+                position: x as f32,
+                length: self.width,
+            },
+            GradientShape::Linear { .. } => {
+                // project the pixel on the direction through the center, the corners end up at 0 and length
+                let (sin, cos) = self.direction;
+                let dx = x as f32 - self.width / 2.0;
+                let dy = y as f32 - self.height / 2.0;
+                Location::Band {
+                    position: dx * sin + dy * cos + self.length / 2.0,
+                    length: self.length,
+                }
+            }
+            GradientShape::Radial { cx, cy, radius } => {
+                let dx = x as f32 - cx * self.width;
+                let dy = y as f32 - cy * self.height;
+                let half_diagonal = (self.width.powi(2) + self.height.powi(2)).sqrt() / 2.0;
+                Location::Radial((dx * dx + dy * dy).sqrt() / (radius * half_diagonal).max(1.0))
+            }
+            GradientShape::Vignette => {
+                // elliptic distance following the image aspect, 1.0 in the corners
+                let dx = (x as f32 / self.width - 0.5) * 2.0;
+                let dy = (y as f32 / self.height - 0.5) * 2.0;
+                Location::Radial((dx * dx + dy * dy).sqrt() / std::f32::consts::SQRT_2)
+            }
+        }
+    }
+
+    /// Alpha of a band where position is the distance in pixels from the start of a band with the given length
+    fn band(&self, position: f32, length: f32) -> f32 {
+        let spec = self.spec;
+        let normalized = position / length;
+        let factor = if normalized > spec.midpoint {
+            self.fade * spec.bottom_strength
+        } else {
+            spec.top_strength
+        };
+        // if 0.5 0 at middle, 1 at top/bottom, otherwise shift position toward top/bottom
+        let distance_from_middle = (normalized - spec.midpoint).abs() * 2.0;
+        (factor * distance_from_middle.powf(spec.exponent)).clamp(0.0, 1.0)
+    }
+
+    fn radial(&self, distance: f32) -> f32 {
+        let strength = self.fade * self.spec.bottom_strength;
+        (strength * distance.min(1.0).powf(self.spec.exponent)).clamp(0.0, 1.0)
+    }
+}
+
+/// Color and alpha of a multi stop gradient, interpolated in Oklab and stored as a lookup table
+struct StopGradient {
+    lut: Vec<Rgba<u8>>,
+}
+
+impl StopGradient {
+    const SIZE: usize = 256;
+
+    fn new(stops: &[ColorStop]) -> Self {
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        let oklab: Vec<(f32, Oklab, f32)> = stops
+            .iter()
+            .map(|s| {
+                (
+                    s.position,
+                    s.color.into_format::<f32>().into_color(),
+                    s.alpha,
+                )
+            })
+            .collect();
+
+        let lut = (0..Self::SIZE)
+            .map(|i| {
+                let t = i as f32 / (Self::SIZE - 1) as f32;
+                let (color, alpha) = match oklab.iter().position(|(p, _, _)| *p > t) {
+                    Some(0) => (oklab[0].1, oklab[0].2),
+                    Some(next) => {
+                        let (p0, c0, a0) = oklab[next - 1];
+                        let (p1, c1, a1) = oklab[next];
+                        let f = (t - p0) / (p1 - p0);
+                        (c0.mix(c1, f), a0 + (a1 - a0) * f)
+                    }
+                    None => oklab
+                        .last()
+                        .map_or((Oklab::new(0.0, 0.0, 0.0), 0.0), |(_, c, a)| (*c, *a)),
+                };
+                let rgb: Srgb<u8> = Srgb::<f32>::from_color(color).clamp().into_format();
+                Rgba([
+                    rgb.red,
+                    rgb.green,
+                    rgb.blue,
+                    (alpha.clamp(0.0, 1.0) * 255.0).round() as u8,
+                ])
+            })
+            .collect();
+        Self { lut }
+    }
+
+    fn at(&self, position: f32) -> Rgba<u8> {
+        self.lut[(position.clamp(0.0, 1.0) * (Self::SIZE - 1) as f32).round() as usize]
+    }
+}
+
+fn create_overlay_image(
+    width: u32,
+    height: u32,
+    gradient_rgb: Srgb<u8>,
+    img: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    fade: f32,
+    spec: &GradientSpec,
+    blending: Blending,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let field = AlphaField::new(spec, width, height, fade);
+    apply_overlay(width, height, img, blending, |x, y| {
+        Rgba([
+            gradient_rgb.red,
+            gradient_rgb.green,
+            gradient_rgb.blue,
+            (field.sample(x, y) * 255.0) as u8,
+        ])
+    })
+}
+
+/// Create the overlay from color stops placed along the gradient shape,
+/// the stops define the alpha so midpoint, exponent, strengths and fade are not used
+fn create_stops_overlay_image(
+    width: u32,
+    height: u32,
+    stops: &[ColorStop],
+    img: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    spec: &GradientSpec,
+    blending: Blending,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let field = AlphaField::new(spec, width, height, 1.0);
+    let gradient = StopGradient::new(stops);
+    apply_overlay(width, height, img, blending, |x, y| {
+        gradient.at(field.position(x, y))
+    })
+}
+
+/// Blend the overlay color given for every pixel on top of the image
+fn apply_overlay<F>(
+    width: u32,
+    height: u32,
+    img: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    blending: Blending,
+    overlay_at: F,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>>
+where
+    F: Fn(u32, u32) -> Rgba<u8> + Sync,
+{
+    let mut output = RgbaImage::new(width, height);
+
+    output
+        .enumerate_rows_mut()
+        .par_bridge()
+        .for_each(|(y, row)| {
+            for (x, _, pixel) in row {
+                let base = img.get_pixel(x, y);
+                let blended = blend_pixels_with(*base, overlay_at(x, y), blending);
+                *pixel = blended;
+            }
+        });
+
+    output
+}
+
+/// Scale the watermark to its share of the image width and blend it at its position
+/// with the alpha of the watermark multiplied by the opacity
+fn composite_watermark(img: &mut RgbaImage, watermark_img: &RgbaImage, watermark: &Watermark) {
+    let (width, height) = img.dimensions();
+    let (src_width, src_height) = watermark_img.dimensions();
+    if src_width == 0 || src_height == 0 {
+        return;
+    }
+    let mut target_width = ((width as f32 * watermark.scale).round() as u32).max(1);
+    let mut target_height =
+        ((src_height as f64 * target_width as f64 / src_width as f64).round() as u32).max(1);
+    // a tall watermark is fitted inside the image keeping its aspect ratio
+    if target_height > height {
+        target_width =
+            ((src_width as f64 * height as f64 / src_height as f64).round() as u32).max(1);
+        target_height = height;
+    }
+    let scaled = image::imageops::resize(
+        watermark_img,
+        target_width,
+        target_height,
+        FilterType::Lanczos3,
+    );
+    let margin = (width as f32 * watermark.margin).round() as i64;
+    let (free_x, free_y) = (
+        width as i64 - target_width as i64,
+        height as i64 - target_height as i64,
+    );
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (free_x - margin, margin),
+        WatermarkPosition::Center => (free_x / 2, free_y / 2),
+        WatermarkPosition::BottomLeft => (margin, free_y - margin),
+        WatermarkPosition::BottomRight => (free_x - margin, free_y - margin),
+    };
+    let opacity = watermark.opacity.clamp(0.0, 1.0);
+    for (sx, sy, pixel) in scaled.enumerate_pixels() {
+        let (px, py) = (x + sx as i64, y + sy as i64);
+        if px < 0 || py < 0 || px >= width as i64 || py >= height as i64 {
+            continue;
+        }
+        let base = img.get_pixel_mut(px as u32, py as u32);
+        let alpha = (pixel[3] as f32 * opacity).round() as u8;
+        *base = blend_over(*base, Rgba([pixel[0], pixel[1], pixel[2], alpha]));
+    }
+}
+
+fn to_lab(flat: &[u8]) -> Vec<Lab> {
+    from_component_slice::<Srgb<u8>>(flat)
+        .iter()
+        .map(|x| x.into_linear().into_color())
+        .collect()
+}
+
+fn calculate_dominant_color(flat: &[u8]) -> Srgb<u8> {
+    let lab = to_lab(flat);
+
+    let kmeans = get_kmeans(1, 10, 1e-5, false, &lab, 42);
+    let dominant_lab = kmeans.centroids[0];
+    let linear_rgb: Srgb<f32> = dominant_lab.into_color();
+    linear_rgb.into_format()
+}
+
+/// Run k-means with k clusters in Lab and return the centroids with their share of the pixels,
+/// largest share first. Clusters without any pixels are left out
+fn calculate_palette(flat: &[u8], k: usize) -> Vec<PaletteColor> {
+    let lab = to_lab(flat);
+    if lab.is_empty() {
+        return Vec::new();
+    }
+
+    let kmeans = get_kmeans(k, 20, 1e-5, false, &lab, 42);
+    let total = lab.len() as f32;
+    let mut counts = vec![0usize; kmeans.centroids.len()];
+    for &index in &kmeans.indices {
+        counts[index as usize] += 1;
+    }
+    let mut palette: Vec<PaletteColor> = kmeans
+        .centroids
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(centroid, count)| {
+            let rgb: Srgb<f32> = (*centroid).into_color();
+            PaletteColor {
+                color: rgb.clamp().into_format(),
+                lab: *centroid,
+                share: count as f32 / total,
+            }
+        })
+        .collect();
+    palette.sort_by(|a, b| b.share.total_cmp(&a.share));
+    palette
+}
+
+fn blend_pixels(base: Rgba<u8>, overlay: Rgba<u8>) -> Rgba<u8> {
+    let alpha = overlay[3] as f32 / 255.0;
+    let inv_alpha = 1.0 - alpha;
+    // use round for the values and not truncate in the convertion
+    let r = (overlay[0] as f32 * alpha + base[0] as f32 * inv_alpha).round() as u8;
+    let g = (overlay[1] as f32 * alpha + base[1] as f32 * inv_alpha).round() as u8;
+    let b = (overlay[2] as f32 * alpha + base[2] as f32 * inv_alpha).round() as u8;
+
+    Rgba([r, g, b, 255])
+}
+
+/// Blend the overlay color onto the base with the blend mode and mix the result in with the
+/// overlay alpha, Normal in sRGB is the plain alpha interpolation of blend_pixels
+fn blend_pixels_with(base: Rgba<u8>, overlay: Rgba<u8>, blending: Blending) -> Rgba<u8> {
+    if blending == Blending::default() {
+        return blend_pixels(base, overlay);
+    }
+    let alpha = overlay[3] as f32 / 255.0;
+    let decode = |p: Rgba<u8>| {
+        let space = blending.space;
+        [space.decode(p[0]), space.decode(p[1]), space.decode(p[2])]
+    };
+    let base_color = decode(base);
+    let overlay_color = decode(overlay);
+    let blended = blending.mode.blend(base_color, overlay_color);
+    // the blend mode only applies as far as the source is opaque, outside of it the overlay
+    // color is used as is
+    let base_alpha = if blending.preserve_alpha {
+        base[3] as f32 / 255.0
+    } else {
+        1.0
+    };
+    let mix = |i: usize| {
+        let color = overlay_color[i] * (1.0 - base_alpha) + blended[i] * base_alpha;
+        blending
+            .space
+            .encode(color * alpha + base_color[i] * (1.0 - alpha))
+    };
+    let out_alpha = if blending.preserve_alpha {
+        base[3]
+    } else {
+        255
+    };
+    Rgba([mix(0), mix(1), mix(2), out_alpha])
+}
+
+/// Porter-Duff source-over of the overlay onto the base, with an opaque base it is the same as
+/// blend_pixels
+pub(crate) fn blend_over(base: Rgba<u8>, overlay: Rgba<u8>) -> Rgba<u8> {
+    let alpha = overlay[3] as f32 / 255.0;
+    let base_alpha = base[3] as f32 / 255.0 * (1.0 - alpha);
+    let out_alpha = alpha + base_alpha;
+    if out_alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let mix = |i: usize| {
+        ((overlay[i] as f32 * alpha + base[i] as f32 * base_alpha) / out_alpha).round() as u8
+    };
+    Rgba([mix(0), mix(1), mix(2), (out_alpha * 255.0).round() as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::Method::GET;
+    use httpmock::MockServer;
+    use image::codecs::png::PngEncoder;
+    use image::{ExtendedColorType, ImageBuffer, ImageEncoder, Rgba, RgbaImage};
+    use palette::Srgb;
+
+    /// A manager that may fetch from the mock server on localhost
+    fn local_manager() -> Manager {
+        local_manager_with_limits(FetchLimits::default())
+    }
+
+    fn local_manager_with_limits(limits: FetchLimits) -> Manager {
+        Manager::build(
+            FetchPolicy {
+                allow_private: true,
+                ..FetchPolicy::default()
+            },
+            limits,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_url_with_mock() {
+        let server = MockServer::start();
+        // Create a small in-memory PNG image
+        let img = ImageBuffer::<Rgba<u8>, _>::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        let mut buf = Vec::new();
+        {
+            let encoder = PngEncoder::new(&mut buf);
+            encoder
+                .write_image(
+                    img.as_raw().as_slice(), // raw pixel data
+                    img.width(),
+                    img.height(),
+                    ExtendedColorType::Rgba8,
+                )
+                .unwrap();
+        }
+
+        // Mock the HTTP GET request
+        server.mock(|when, then| {
+            when.method(GET).path("/test-image");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(buf.clone());
+        });
+        let manager = local_manager();
+        let url = format!("{}/test-image", server.url(""));
+        let result = manager
+            .generate_from_url(url, options(GradientColorType::UserSelected(50, 50, 50)))
+            .await
+            .unwrap();
+
+        // Assert the output is a valid image
+        assert_eq!(result.image.dimensions(), (2, 2));
+        assert_eq!(result.info.color, Srgb::new(50, 50, 50));
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_url_with_resize() {
+        let server = MockServer::start();
+        let img = ImageBuffer::<Rgba<u8>, _>::from_pixel(8, 4, Rgba([0, 255, 0, 255]));
+        let mut buf = Vec::new();
+        PngEncoder::new(&mut buf)
+            .write_image(img.as_raw(), 8, 4, ExtendedColorType::Rgba8)
+            .unwrap();
+        server.mock(|when, then| {
+            when.method(GET).path("/wide-image");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(buf.clone());
+        });
+        let manager = local_manager();
+        let url = format!("{}/wide-image", server.url(""));
+        let mut opts = options(GradientColorType::Dominant);
+        opts.resize = Some(Resize {
+            width: Some(3),
+            height: Some(3),
+            fit: Fit::Cover,
+        });
+        let result = manager.generate_from_url(url, opts).await.unwrap();
+
+        assert_eq!(result.image.dimensions(), (3, 3));
+        assert_eq!(
+            (result.info.source_width, result.info.source_height),
+            (8, 4)
+        );
+        assert_eq!((result.info.width, result.info.height), (3, 3));
+
+        let mut opts = options(GradientColorType::Dominant);
+        opts.resize = Some(Resize {
+            width: Some(4),
+            height: None,
+            fit: Fit::Cover,
+        });
+        let url = format!("{}/wide-image", server.url(""));
+        let info = manager.info_from_url(url, opts).await.unwrap();
+        assert_eq!(
+            info,
+            OverlayInfo {
+                color: Srgb::new(0, 255, 0),
+                source_width: 8,
+                source_height: 4,
+                width: 4,
+                height: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_uses_disk_cache() {
+        let server = MockServer::start();
+        let img = ImageBuffer::<Rgba<u8>, _>::from_pixel(2, 2, Rgba([0, 0, 255, 255]));
+        let mut buf = Vec::new();
+        PngEncoder::new(&mut buf)
+            .write_image(img.as_raw(), 2, 2, ExtendedColorType::Rgba8)
+            .unwrap();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/cached-image");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(buf.clone());
+        });
+        let dir = std::env::temp_dir().join(format!("overlay-fetch-cache-{}", std::process::id()));
+        let disk_cache = Arc::new(DiskCache::open(&dir, 1024 * 1024).unwrap());
+        let manager = local_manager().with_disk_cache(disk_cache);
+        let url = format!("{}/cached-image", server.url(""));
+        for _ in 0..2 {
+            let info = manager
+                .info_from_url(url.clone(), options(GradientColorType::Dominant))
+                .await
+                .unwrap();
+            assert_eq!(info.color, Srgb::new(0, 0, 255));
+        }
+
+        mock.assert_hits(1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_url_upstream_not_found() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/missing");
+            then.status(404);
+        });
+        let manager = local_manager();
+        let url = format!("{}/missing", server.url(""));
+        let result = manager
+            .generate_from_url(url, options(GradientColorType::Dominant))
+            .await;
+
+        match result {
+            Err(OverlayError::UpstreamStatus(status)) => assert_eq!(status.as_u16(), 404),
+            other => panic!("expected UpstreamStatus, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_draws_text_layers() {
+        let fonts = FontLibrary::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fonts"))
+            .unwrap();
+        let manager = local_manager().with_fonts(Arc::new(fonts));
+        let png = encode_image(
+            &dummy_image(64, 32, Rgba([0, 0, 0, 255])),
+            ImageFormat::Png,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let layer = TextLayer {
+            content: "Hi".to_string(),
+            font: "DejaVuSansMono".to_string(),
+            size: 16.0,
+            color: Srgb::new(255, 255, 0),
+            align: text::TextAlign::Center,
+            vertical_align: text::VerticalAlign::Center,
+            area: text::TextBox {
+                x: 0.0,
+                y: 0.0,
+                width: 1.0,
+                height: 1.0,
+            },
+            max_lines: None,
+        };
+        let mut opts = options(GradientColorType::UserSelected(0, 0, 0));
+        opts.text = vec![layer.clone()];
+        let result = manager.generate_from_bytes(&png, opts).await.unwrap();
+        assert!(
+            result
+                .image
+                .pixels()
+                .any(|p| p[0] > 200 && p[1] > 200 && p[2] < 50)
+        );
+
+        let mut opts = options(GradientColorType::Dominant);
+        opts.text = vec![TextLayer {
+            font: "Missing".to_string(),
+            ..layer
+        }];
+        assert!(matches!(
+            manager.generate_from_bytes(&png, opts).await,
+            Err(OverlayError::UnknownFont(name)) if name == "Missing"
+        ));
+    }
+
+    fn watermark(source: WatermarkSource, position: WatermarkPosition) -> Watermark {
+        Watermark {
+            source,
+            position,
+            margin: 0.1,
+            scale: 0.25,
+            opacity: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_composite_watermark_position_and_scale() {
+        let logo = RgbaImage::from_pixel(8, 4, Rgba([255, 0, 0, 255]));
+        let red = |img: &RgbaImage| -> Vec<(u32, u32)> {
+            img.enumerate_pixels()
+                .filter(|(_, _, p)| p[0] == 255)
+                .map(|(x, y, _)| (x, y))
+                .collect()
+        };
+
+        let mut img = dummy_image(40, 20, Rgba([0, 0, 0, 255]));
+        let source = WatermarkSource::Asset("logo".into());
+        composite_watermark(
+            &mut img,
+            &logo,
+            &watermark(source.clone(), WatermarkPosition::BottomRight),
+        );
+        // 10 pixels wide keeping the aspect ratio, 4 pixels margin from the bottom right corner
+        let pixels = red(&img);
+        assert_eq!(pixels.len(), 10 * 5);
+        assert_eq!(pixels.first(), Some(&(26, 11)));
+        assert_eq!(pixels.last(), Some(&(35, 15)));
+
+        let mut img = dummy_image(40, 20, Rgba([0, 0, 0, 255]));
+        composite_watermark(
+            &mut img,
+            &logo,
+            &watermark(source.clone(), WatermarkPosition::TopLeft),
+        );
+        assert_eq!(red(&img).first(), Some(&(4, 4)));
+
+        let mut img = dummy_image(40, 20, Rgba([0, 0, 0, 255]));
+        composite_watermark(
+            &mut img,
+            &logo,
+            &Watermark {
+                opacity: 0.5,
+                ..watermark(source, WatermarkPosition::Center)
+            },
+        );
+        assert_eq!(*img.get_pixel(20, 10), Rgba([128, 0, 0, 255]));
+        assert_eq!(*img.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+
+        // a tall logo at full scale is fitted to the height of the image
+        let tall = RgbaImage::from_pixel(2, 1000, Rgba([255, 0, 0, 255]));
+        let mut img = dummy_image(400, 20, Rgba([0, 0, 0, 255]));
+        composite_watermark(
+            &mut img,
+            &tall,
+            &Watermark {
+                scale: 1.0,
+                margin: 0.0,
+                ..watermark(
+                    WatermarkSource::Asset("tall".into()),
+                    WatermarkPosition::TopLeft,
+                )
+            },
+        );
+        let pixels = red(&img);
+        assert!(!pixels.is_empty());
+        assert!(pixels.iter().all(|&(x, _)| x < 1), "{:?}", pixels);
+        assert_eq!(pixels.last(), Some(&(0, 19)));
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_watermark_sources() {
+        let server = MockServer::start();
+        let logo = encode_image(
+            &dummy_image(4, 4, Rgba([0, 0, 255, 255])),
+            ImageFormat::Png,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let logo_mock = server.mock(|when, then| {
+            when.method(GET).path("/logo.png");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(logo.clone());
+        });
+        let manager = local_manager();
+        let png = encode_image(
+            &dummy_image(20, 20, Rgba([0, 0, 0, 255])),
+            ImageFormat::Png,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+
+        let mut opts = options(GradientColorType::UserSelected(0, 0, 0));
+        opts.watermark = Some(watermark(
+            WatermarkSource::Url(server.url("/logo.png")),
+            WatermarkPosition::Center,
+        ));
+        let result = manager.generate_from_bytes(&png, opts).await.unwrap();
+        logo_mock.assert_hits(1);
+        assert_eq!(*result.image.get_pixel(10, 10), Rgba([0, 0, 255, 255]));
+
+        let mut opts = options(GradientColorType::UserSelected(0, 0, 0));
+        opts.watermark = Some(watermark(
+            WatermarkSource::Asset("missing".into()),
+            WatermarkPosition::Center,
+        ));
+        assert!(matches!(
+            manager.generate_from_bytes(&png, opts).await,
+            Err(OverlayError::UnknownAsset(name)) if name == "missing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_animation() {
+        let frames = [Rgba([0, 0, 255, 255]), Rgba([0, 255, 0, 255])]
+            .iter()
+            .map(|color| AnimationFrame {
+                image: dummy_image(8, 8, *color),
+                delay: Duration::from_millis(80),
+            })
+            .collect();
+        let source = Animation {
+            frames,
+            loop_count: animation::LoopCount::Forever,
+        };
+        let gif = animation::encode_animation(&source, ImageFormat::Gif).unwrap();
+        let manager = local_manager();
+
+        let mut opts = options(GradientColorType::UserSelected(255, 0, 0));
+        opts.animate = true;
+        opts.resize = Some(Resize {
+            width: Some(4),
+            height: None,
+            fit: Fit::Cover,
+        });
+        let result = manager.generate_from_bytes(&gif, opts).await.unwrap();
+        let animation = result.animation.unwrap();
+        assert_eq!(animation.loop_count, animation::LoopCount::Forever);
+        assert_eq!(animation.frames.len(), 2);
+        for frame in &animation.frames {
+            assert_eq!(frame.delay, Duration::from_millis(80));
+            assert_eq!(frame.image.dimensions(), (4, 4));
+            // the overlay is applied to every frame
+            assert!(frame.image.get_pixel(0, 0)[0] > 0);
+        }
+        assert_eq!(result.image, animation.frames[0].image);
+        assert_eq!(result.info.source_width, 8);
+
+        let result = manager
+            .generate_from_bytes(&gif, options(GradientColorType::Dominant))
+            .await
+            .unwrap();
+        assert!(result.animation.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_animation_resize_limits() {
+        let frames = (0..2)
+            .map(|_| AnimationFrame {
+                image: dummy_image(8, 8, Rgba([0, 0, 255, 255])),
+                delay: Duration::from_millis(80),
+            })
+            .collect();
+        let source = Animation {
+            frames,
+            loop_count: animation::LoopCount::Forever,
+        };
+        let gif = animation::encode_animation(&source, ImageFormat::Gif).unwrap();
+        // the decoded frames fit but the frames resized to 64x64 do not
+        let manager = local_manager().with_decode_limits(DecodeLimits {
+            max_alloc: 8 * 1024,
+            ..DecodeLimits::default()
+        });
+        let resized = |size| {
+            let mut opts = options(GradientColorType::UserSelected(255, 0, 0));
+            opts.animate = true;
+            opts.resize = Some(Resize {
+                width: Some(size),
+                height: Some(size),
+                fit: Fit::Fill,
+            });
+            opts
+        };
+        assert!(matches!(
+            manager.generate_from_bytes(&gif, resized(64)).await,
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+        assert!(manager.generate_from_bytes(&gif, resized(16)).await.is_ok());
+    }
+
+    #[test]
+    fn test_select_frames_gradient_color() {
+        let blue = dummy_image(4, 4, Rgba([0, 0, 255, 255]));
+        let red = dummy_image(4, 4, Rgba([255, 0, 0, 255]));
+        let only_blue = select_frames_gradient_color(&GradientColorType::Dominant, 4, 4, &[&blue]);
+        let mixed =
+            select_frames_gradient_color(&GradientColorType::Dominant, 4, 4, &[&blue, &red]);
+        assert_eq!(only_blue, Srgb::new(0, 0, 255));
+        assert!(mixed.red > 0 && mixed.blue > 0);
+    }
+
+    /// A jpeg with the left half red and the right half blue and the exif orientation
+    fn oriented_jpeg(orientation: u16) -> Vec<u8> {
+        let img = RgbaImage::from_fn(8, 4, |x, _| match x < 4 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 255]),
+        });
+        let jpeg = encode_image(
+            &img,
+            ImageFormat::Jpeg,
+            100,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        metadata::embed_jpeg_exif(jpeg, &metadata::exif_with_orientation(orientation))
     }
-}
 
-fn select_gradient_color(
-    select: GradientColorType,
-    width: u32,
-    height: u32,
-    img: &image::ImageBuffer<Rgba<u8>, Vec<u8>>,
-) -> Srgb<u8> {
-    match select {
-        GradientColorType::Dominant => {
-            let flat: Vec<u8> = img.pixels().flat_map(|p| p.0[..3].to_vec()).collect();
-            calculate_dominant_color(&flat)
+    #[test]
+    fn test_decode_image_orientation() {
+        let (img, metadata) = decode_image(&oriented_jpeg(6), &DecodeLimits::default()).unwrap();
+        // rotated 90 degrees clockwise the left half ends up at the top
+        let img = img.into_rgba8();
+        assert_eq!(img.dimensions(), (4, 8));
+        assert!(img.get_pixel(2, 1)[0] > 200 && img.get_pixel(2, 1)[2] < 50);
+        assert!(img.get_pixel(2, 6)[2] > 200 && img.get_pixel(2, 6)[0] < 50);
+        assert_eq!(metadata.exif, Some(metadata::exif_with_orientation(1)));
+
+        let (img, _) = decode_image(&oriented_jpeg(1), &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 4));
+        let (_, metadata) = decode_image(
+            &encode_image(
+                &dummy_image(2, 2, Rgba([0, 0, 0, 255])),
+                ImageFormat::Png,
+                80,
+                WHITE,
+                &ImageMetadata::default(),
+            )
+            .unwrap(),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(metadata, ImageMetadata::default());
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_metadata() {
+        let manager = local_manager();
+        let jpeg = oriented_jpeg(8);
+        let result = manager
+            .generate_from_bytes(&jpeg, options(GradientColorType::Dominant))
+            .await
+            .unwrap();
+        assert_eq!(result.image.dimensions(), (4, 8));
+        assert_eq!(result.info.source_width, 4);
+        assert_eq!(result.metadata, ImageMetadata::default());
+
+        let mut opts = options(GradientColorType::Dominant);
+        opts.preserve_metadata = true;
+        let result = manager.generate_from_bytes(&jpeg, opts).await.unwrap();
+        let exif = metadata::exif_with_orientation(1);
+        assert_eq!(result.metadata.exif.as_ref(), Some(&exif));
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let encoded = encode_image(&result.image, format, 80, WHITE, &result.metadata).unwrap();
+            let (img, metadata) = decode_image(&encoded, &DecodeLimits::default()).unwrap();
+            assert_eq!((img.width(), img.height()), (4, 8));
+            // the png decoder does not read exif
+            if format != ImageFormat::Png {
+                assert_eq!(metadata.exif.as_ref(), Some(&exif), "{:?}", format);
+            }
         }
-        GradientColorType::DominantBottom => {
-            let flat: Vec<u8> = (0..width)
-                .flat_map(|x| {
-                    let pixel = img.get_pixel(x, height - 1); // y = 0 for the first row
-                    pixel.0[..3].to_vec() // RGB only
-                })
-                .collect();
-            calculate_dominant_color(&flat)
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bytes_icc_profile() {
+        let manager = local_manager();
+        for (name, expected) in [
+            ("display-p3.png", Srgb::new(215, 93, 31)),
+            ("adobe-rgb.jpg", Srgb::new(66, 151, 96)),
+        ] {
+            let source = std::fs::read(format!(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/images/{}"),
+                name
+            ))
+            .unwrap();
+            let result = manager
+                .generate_from_bytes(&source, options(GradientColorType::Dominant))
+                .await
+                .unwrap();
+            // the dominant color is the converted sRGB color, not the raw values of the source
+            assert_eq!(result.info.color, expected, "{}", name);
         }
-        GradientColorType::UserSelected(r, g, b) => Srgb::<u8>::new(r, g, b),
     }
-}
 
-fn create_overlay_image(
-    width: u32,
-    height: u32,
-    gradient_rgb: Srgb<u8>,
-    img: ImageBuffer<Rgba<u8>, Vec<u8>>,
-    fade: f32,
-) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    let mut output = RgbaImage::new(width, height);
+    #[test]
+    fn test_decode_image_limits() {
+        let png = encode_image(
+            &dummy_image(8, 4, Rgba([0, 0, 0, 255])),
+            ImageFormat::Png,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert!(decode_image(&png, &DecodeLimits::default()).is_ok());
 
-    output
-        .enumerate_rows_mut()
-        .par_bridge()
-        .for_each(|(y, row)| {
-            let normalized_y = y as f32 / height as f32;
-            let factor = if y as f32 > ((1.0 - 0.4) * height as f32 / 2f32).round() {
-                fade
-            } else {
-                1.0
-            };
-            // if 0.5 0 at middle, 1 at top/bottom, otherwise shift position toward top/bottom
-            let distance_from_middle = (normalized_y - 0.4).abs() * 2.0;
-            let alpha = factor * distance_from_middle.powf(2.0);
-
-            // bottom to top
-            //let alpha = (y as f32 / height as f32).powf(2.0);
-            let overlay = Rgba([
-                gradient_rgb.red,
-                gradient_rgb.green,
-                gradient_rgb.blue,
-                (alpha * 255.0) as u8,
-            ]);
+        let narrow = DecodeLimits {
+            max_width: 4,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode_image(&png, &narrow),
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+        // 8 * 4 pixels need 128 bytes as rgba
+        let small_alloc = DecodeLimits {
+            max_alloc: 100,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode_image(&png, &small_alloc),
+            Err(OverlayError::ImageTooLarge(_))
+        ));
+        assert!(matches!(
+            decode_image(b"not an image", &DecodeLimits::default()),
+            Err(OverlayError::Decode(_))
+        ));
+    }
 
-            for (x, _, pixel) in row {
-                let base = img.get_pixel(x, y);
-                let blended = blend_pixels(*base, overlay);
-                *pixel = blended;
-            }
+    #[test]
+    fn test_prepare_image_downscales_large_sources() {
+        let png = encode_image(
+            &dummy_image(8, 4, Rgba([0, 0, 0, 255])),
+            ImageFormat::Png,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let limits = DecodeLimits {
+            downscale_above: Some(4),
+            ..DecodeLimits::default()
+        };
+        let (img, info, _) =
+            prepare_image(&png, &options(GradientColorType::Dominant), &limits).unwrap();
+
+        assert_eq!(img.dimensions(), (4, 2));
+        assert_eq!((info.source_width, info.source_height), (8, 4));
+        assert_eq!((info.width, info.height), (4, 2));
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_url_corrupt_image() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/corrupt");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body("definitely not a png");
         });
+        let manager = local_manager();
+        let url = format!("{}/corrupt", server.url(""));
+        let result = manager
+            .generate_from_url(url, options(GradientColorType::Dominant))
+            .await;
 
-    output
-}
+        assert!(matches!(result, Err(OverlayError::Decode(_))));
+    }
 
-fn calculate_dominant_color(flat: &Vec<u8>) -> Srgb<u8> {
-    let lab: Vec<Lab> = from_component_slice::<Srgb<u8>>(&flat)
-        .iter()
-        .map(|x| x.into_linear().into_color())
-        .collect();
+    #[tokio::test]
+    async fn test_generate_from_url_invalid_url() {
+        let manager = Manager::build(FetchPolicy::default(), FetchLimits::default());
+        let result = manager
+            .generate_from_url(
+                "not a url".to_string(),
+                options(GradientColorType::Dominant),
+            )
+            .await;
 
-    let kmeans = get_kmeans(1, 10, 1e-5, false, &lab, 42);
-    let dominant_lab = kmeans.centroids[0];
-    let linear_rgb: Srgb<f32> = dominant_lab.into_color();
-    linear_rgb.into_format()
-}
+        assert!(matches!(result, Err(OverlayError::InvalidUrl(_))));
+    }
 
-fn blend_pixels(base: Rgba<u8>, overlay: Rgba<u8>) -> Rgba<u8> {
-    let alpha = overlay[3] as f32 / 255.0;
-    let inv_alpha = 1.0 - alpha;
-    // use round for the values and not truncate in the convertion
-    let r = (overlay[0] as f32 * alpha + base[0] as f32 * inv_alpha).round() as u8;
-    let g = (overlay[1] as f32 * alpha + base[1] as f32 * inv_alpha).round() as u8;
-    let b = (overlay[2] as f32 * alpha + base[2] as f32 * inv_alpha).round() as u8;
+    #[tokio::test]
+    async fn test_fetch_refuses_private_addresses() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/internal");
+            then.status(200).body("secret");
+        });
+        let manager = Manager::build(FetchPolicy::default(), FetchLimits::default());
+        for url in [
+            server.url("/internal"),
+            format!("http://localhost:{}/internal", server.port()),
+        ] {
+            let result = manager
+                .generate_from_url(url, options(GradientColorType::Dominant))
+                .await;
+            assert!(matches!(
+                result,
+                Err(OverlayError::Forbidden(PolicyViolation::Address(..)))
+            ));
+        }
+        let result = manager
+            .palette_from_url("file:///etc/passwd".to_string(), 3)
+            .await;
+        assert!(matches!(
+            result,
+            Err(OverlayError::Forbidden(PolicyViolation::Scheme(_)))
+        ));
+        mock.assert_hits(0);
+    }
 
-    Rgba([r, g, b, 255])
-}
+    #[tokio::test]
+    async fn test_fetch_checks_redirects() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/redirect");
+            then.status(302).header(
+                "Location",
+                format!("http://localhost:{}/internal", server.port()),
+            );
+        });
+        let target = server.mock(|when, then| {
+            when.method(GET).path("/internal");
+            then.status(200).body("secret");
+        });
+        let manager = Manager::build(
+            FetchPolicy {
+                deny_hosts: vec!["localhost".into()],
+                allow_private: true,
+                ..FetchPolicy::default()
+            },
+            FetchLimits::default(),
+        );
+        let result = manager
+            .generate_from_url(
+                server.url("/redirect"),
+                options(GradientColorType::Dominant),
+            )
+            .await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use httpmock::Method::GET;
-    use httpmock::MockServer;
-    use image::codecs::png::PngEncoder;
-    use image::{ExtendedColorType, ImageBuffer, ImageEncoder, Rgba, RgbaImage};
-    use palette::Srgb;
+        assert!(matches!(
+            result,
+            Err(OverlayError::Forbidden(PolicyViolation::HostDenied(_)))
+        ));
+        target.assert_hits(0);
+    }
 
     #[tokio::test]
-    async fn test_generate_from_url_with_mock() {
+    async fn test_fetch_rejects_large_body() {
         let server = MockServer::start();
-        // Create a small in-memory PNG image
-        let img = ImageBuffer::<Rgba<u8>, _>::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
-        let mut buf = Vec::new();
-        {
-            let encoder = PngEncoder::new(&mut buf);
-            encoder
-                .write_image(
-                    &img.as_raw().as_slice(), // raw pixel data
-                    img.width(),
-                    img.height(),
-                    ExtendedColorType::Rgba8,
-                )
-                .unwrap();
-        }
+        server.mock(|when, then| {
+            when.method(GET).path("/large");
+            then.status(200).body(vec![0u8; 2048]);
+        });
+        let manager = local_manager_with_limits(FetchLimits {
+            max_body_bytes: 1024,
+            ..FetchLimits::default()
+        });
+        let result = manager
+            .generate_from_url(server.url("/large"), options(GradientColorType::Dominant))
+            .await;
 
-        // Mock the HTTP GET request
+        assert!(matches!(result, Err(OverlayError::BodyTooLarge(1024))));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_timeout() {
+        let server = MockServer::start();
         server.mock(|when, then| {
-            when.method(GET).path("/test-image");
-            then.status(200)
-                .header("Content-Type", "image/png")
-                .body(buf.clone());
+            when.method(GET).path("/slow");
+            then.status(200).delay(Duration::from_millis(500));
+        });
+        let timeout = Duration::from_millis(100);
+        let manager = local_manager_with_limits(FetchLimits {
+            timeout,
+            ..FetchLimits::default()
         });
-        let manager = Manager::build();
-        let url = format!("{}/test-image", server.url(""));
         let result = manager
-            .generate_from_url(url, GradientColorType::UserSelected(50, 50, 50), 1.0)
+            .generate_from_url(server.url("/slow"), options(GradientColorType::Dominant))
             .await;
 
-        // Assert the output is a valid image
-        assert_eq!(result.dimensions(), (2, 2));
+        assert!(matches!(result, Err(OverlayError::Timeout(t)) if t == timeout));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_too_many_redirects() {
+        let server = MockServer::start();
+        let redirect = server.mock(|when, then| {
+            when.method(GET).path("/loop");
+            then.status(302).header("Location", "/loop");
+        });
+        let manager = local_manager_with_limits(FetchLimits {
+            max_redirects: 2,
+            ..FetchLimits::default()
+        });
+        let result = manager
+            .generate_from_url(server.url("/loop"), options(GradientColorType::Dominant))
+            .await;
+
+        assert!(matches!(result, Err(OverlayError::TooManyRedirects(2))));
+        redirect.assert_hits(3);
     }
 
     #[test]
     fn test_select_gradient_color_dominant() {
         let img = dummy_image(2, 2, Rgba([10, 20, 30, 255]));
-        let result = select_gradient_color(GradientColorType::Dominant, 2, 2, &img);
+        let result = select_gradient_color(&GradientColorType::Dominant, 2, 2, &img);
         assert_eq!(result, Srgb::new(10, 20, 30));
     }
 
@@ -216,14 +2204,14 @@ mod tests {
         img.put_pixel(0, 1, Rgba([100, 150, 200, 255]));
         img.put_pixel(1, 1, Rgba([100, 150, 200, 255]));
 
-        let result = select_gradient_color(GradientColorType::DominantBottom, 2, 2, &img);
+        let result = select_gradient_color(&GradientColorType::DominantBottom, 2, 2, &img);
         assert_eq!(result, Srgb::new(100, 150, 200));
     }
 
     #[test]
     fn test_select_gradient_color_user_selected() {
         let result = select_gradient_color(
-            GradientColorType::UserSelected(1, 2, 3),
+            &GradientColorType::UserSelected(1, 2, 3),
             0,
             0,
             &dummy_image(1, 1, Rgba([0, 0, 0, 255])),
@@ -239,7 +2227,15 @@ mod tests {
         let dominant_color = Srgb::new(255, 0, 0); // Red
 
         let img = dummy_image(width, height, base_color);
-        let result = create_overlay_image(width, height, dominant_color, img, 1.0);
+        let result = create_overlay_image(
+            width,
+            height,
+            dominant_color,
+            img,
+            1.0,
+            &GradientSpec::default(),
+            Blending::default(),
+        );
 
         assert_eq!(result.width(), width);
         assert_eq!(result.height(), height);
@@ -253,7 +2249,15 @@ mod tests {
         let dominant_color = Srgb::new(255, 0, 0); // Red
 
         let img = dummy_image(width, height, base_color);
-        let result = create_overlay_image(width, height, dominant_color, img, 1.0);
+        let result = create_overlay_image(
+            width,
+            height,
+            dominant_color,
+            img,
+            1.0,
+            &GradientSpec::default(),
+            Blending::default(),
+        );
 
         // Check that the output pixel is not the same as the base (i.e., blending occurred)
         let top_pixel = result.get_pixel(0, 0);
@@ -263,14 +2267,257 @@ mod tests {
         assert_ne!(bottom_pixel, &base_color);
     }
 
+    #[test]
+    fn test_resize_image_fits() {
+        let img = DynamicImage::ImageRgba8(dummy_image(8, 4, Rgba([200, 0, 0, 255])));
+        let resize = |width, height, fit| Resize { width, height, fit };
+
+        let cover = resize_image(img.clone(), resize(Some(4), Some(4), Fit::Cover));
+        assert_eq!((cover.width(), cover.height()), (4, 4));
+        assert_eq!(cover.to_rgba8().get_pixel(0, 0), &Rgba([200, 0, 0, 255]));
+
+        let fill = resize_image(img.clone(), resize(Some(2), Some(6), Fit::Fill));
+        assert_eq!((fill.width(), fill.height()), (2, 6));
+
+        let inside = resize_image(img.clone(), resize(Some(4), Some(4), Fit::Inside));
+        assert_eq!((inside.width(), inside.height()), (4, 2));
+
+        let contain = resize_image(img.clone(), resize(Some(4), Some(4), Fit::Contain)).to_rgba8();
+        assert_eq!(contain.dimensions(), (4, 4));
+        assert_eq!(contain.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(contain.get_pixel(0, 1), &Rgba([200, 0, 0, 255]));
+
+        let width_only = resize_image(img, resize(Some(4), None, Fit::Cover));
+        assert_eq!((width_only.width(), width_only.height()), (4, 2));
+
+        // the side from the aspect ratio of a tall, narrow source is capped
+        let tall = DynamicImage::ImageRgba8(dummy_image(2, 4096, Rgba([200, 0, 0, 255])));
+        let width_only = resize_image(tall.clone(), resize(Some(256), None, Fit::Cover));
+        assert_eq!(
+            (width_only.width(), width_only.height()),
+            (256, MAX_DIMENSION)
+        );
+        let wide = DynamicImage::ImageRgba8(dummy_image(4096, 2, Rgba([200, 0, 0, 255])));
+        let height_only = resize_image(wide, resize(None, Some(256), Fit::Cover));
+        assert_eq!(
+            (height_only.width(), height_only.height()),
+            (MAX_DIMENSION, 256)
+        );
+    }
+
+    #[test]
+    fn test_encode_image_formats() {
+        let img = dummy_image(4, 4, Rgba([10, 20, 30, 255]));
+
+        let png =
+            encode_image(&img, ImageFormat::Png, 80, WHITE, &ImageMetadata::default()).unwrap();
+        assert!(png.starts_with(&[0x89, b'P', b'N', b'G']));
+
+        let jpeg = encode_image(
+            &img,
+            ImageFormat::Jpeg,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert!(jpeg.starts_with(&[0xFF, 0xD8, 0xFF]));
+
+        let webp = encode_image(
+            &img,
+            ImageFormat::WebP,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!(&webp[8..12], b"WEBP");
+
+        let avif = encode_image(
+            &img,
+            ImageFormat::Avif,
+            80,
+            WHITE,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!(&avif[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn test_create_overlay_image_gradient_spec() {
+        let base_color = Rgba([0, 0, 0, 255]);
+        let img = dummy_image(1, 10, base_color);
+        let spec = GradientSpec {
+            shape: GradientShape::Vertical,
+            midpoint: 0.5,
+            exponent: 1.0,
+            top_strength: 0.0,
+            bottom_strength: 1.0,
+        };
+        let result = create_overlay_image(
+            1,
+            10,
+            Srgb::new(255, 0, 0),
+            img,
+            1.0,
+            &spec,
+            Blending::default(),
+        );
+
+        // no overlay at the midpoint and above it since the top strength is zero
+        for y in 0..=5 {
+            assert_eq!(result.get_pixel(0, y), &base_color, "row {}", y);
+        }
+        // linear falloff below the midpoint, about 0.8 alpha at the last row
+        let last = result.get_pixel(0, 9);
+        assert!((202..=204).contains(&last[0]), "got {:?}", last);
+    }
+
+    #[test]
+    fn test_alpha_field_shapes() {
+        let spec = |shape| GradientSpec {
+            shape,
+            ..GradientSpec::default()
+        };
+
+        let horizontal = spec(GradientShape::Horizontal);
+        let field = AlphaField::new(&horizontal, 10, 4, 1.0);
+        // constant along the columns, clear at the midpoint
+        assert_eq!(field.sample(0, 0), field.sample(0, 3));
+        assert_eq!(field.sample(4, 2), 0.0);
+        assert!(field.sample(9, 0) > 0.0);
+
+        // a linear gradient at 0 degrees is the same as the vertical one
+        let vertical = spec(GradientShape::Vertical);
+        let linear = spec(GradientShape::Linear { angle: 0.0 });
+        let vertical_field = AlphaField::new(&vertical, 4, 10, 1.0);
+        let linear_field = AlphaField::new(&linear, 4, 10, 1.0);
+        for y in 0..10 {
+            assert!((vertical_field.sample(1, y) - linear_field.sample(1, y)).abs() < 1e-4);
+        }
+
+        let radial = spec(GradientShape::Radial {
+            cx: 0.25,
+            cy: 0.5,
+            radius: 0.5,
+        });
+        let field = AlphaField::new(&radial, 20, 20, 1.0);
+        assert_eq!(field.sample(5, 10), 0.0);
+        assert_eq!(field.sample(19, 19), 1.0);
+
+        let vignette = spec(GradientShape::Vignette);
+        let field = AlphaField::new(&vignette, 20, 10, 1.0);
+        assert_eq!(field.sample(10, 5), 0.0);
+        assert!(field.sample(0, 0) > 0.9);
+    }
+
+    #[test]
+    fn test_stop_gradient_interpolation() {
+        let stops = [
+            ColorStop {
+                position: 1.0,
+                color: Srgb::new(255, 255, 255),
+                alpha: 0.0,
+            },
+            ColorStop {
+                position: 0.0,
+                color: Srgb::new(0, 0, 0),
+                alpha: 1.0,
+            },
+        ];
+        let gradient = StopGradient::new(&stops);
+
+        assert_eq!(gradient.at(0.0), Rgba([0, 0, 0, 255]));
+        assert_eq!(gradient.at(1.0), Rgba([255, 255, 255, 0]));
+        // halfway in oklab is a lightness of 0.5, a darker gray than the srgb average
+        let middle = gradient.at(0.5);
+        assert!(
+            middle[0] == middle[1] && middle[1] == middle[2],
+            "{:?}",
+            middle
+        );
+        assert!((95..=105).contains(&middle[0]), "{:?}", middle);
+        assert!((127..=128).contains(&middle[3]), "{:?}", middle);
+    }
+
+    #[test]
+    fn test_create_stops_overlay_image() {
+        let img = dummy_image(1, 3, Rgba([0, 0, 0, 255]));
+        let stops = [
+            ColorStop {
+                position: 0.0,
+                color: Srgb::new(255, 0, 0),
+                alpha: 1.0,
+            },
+            ColorStop {
+                position: 0.5,
+                color: Srgb::new(0, 0, 0),
+                alpha: 0.0,
+            },
+        ];
+        let result = create_stops_overlay_image(
+            1,
+            3,
+            &stops,
+            img,
+            &GradientSpec::default(),
+            Blending::default(),
+        );
+
+        assert_eq!(result.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        // after the last stop the last stop is used
+        assert_eq!(result.get_pixel(0, 2), &Rgba([0, 0, 0, 255]));
+        assert_eq!(
+            select_gradient_color(&GradientColorType::Stops(stops.to_vec()), 1, 3, &result),
+            Srgb::new(255, 0, 0)
+        );
+    }
+
     #[test]
     fn test_calculate_dominant_color_single_color() {
-        let red_pixel = vec![255, 0, 0];
+        let red_pixel = [255, 0, 0];
         let flat: Vec<u8> = red_pixel.repeat(10);
         let dominant = calculate_dominant_color(&flat);
         assert_eq!(dominant, Srgb::new(255, 0, 0));
     }
 
+    #[test]
+    fn test_calculate_palette_shares() {
+        let mut flat = [255u8, 0, 0].repeat(30);
+        flat.extend([0u8, 0, 255].repeat(10));
+        let palette = calculate_palette(&flat, 2);
+
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette[0].color, Srgb::new(255, 0, 0));
+        assert_eq!(palette[0].share, 0.75);
+        assert_eq!(palette[1].color, Srgb::new(0, 0, 255));
+        assert_eq!(palette[1].share, 0.25);
+    }
+
+    #[tokio::test]
+    async fn test_palette_from_url_with_mock() {
+        let server = MockServer::start();
+        let img = ImageBuffer::<Rgba<u8>, _>::from_pixel(2, 2, Rgba([0, 128, 0, 255]));
+        let mut buf = Vec::new();
+        PngEncoder::new(&mut buf)
+            .write_image(img.as_raw(), 2, 2, ExtendedColorType::Rgba8)
+            .unwrap();
+        server.mock(|when, then| {
+            when.method(GET).path("/palette-image");
+            then.status(200)
+                .header("Content-Type", "image/png")
+                .body(buf.clone());
+        });
+        let manager = local_manager();
+        let url = format!("{}/palette-image", server.url(""));
+        let palette = manager.palette_from_url(url, 3).await.unwrap();
+
+        assert_eq!(palette.len(), 1);
+        assert_eq!(palette[0].color, Srgb::new(0, 128, 0));
+        assert_eq!(palette[0].share, 1.0);
+    }
+
     #[test]
     fn test_blend_pixels_half_alpha() {
         let base = Rgba([100, 100, 100, 255]);
@@ -309,6 +2556,182 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    fn srgb(mode: BlendMode) -> Blending {
+        Blending {
+            mode,
+            ..Blending::default()
+        }
+    }
+
+    #[test]
+    fn test_linear_lookup_tables_round_trip() {
+        for v in 0..=255u8 {
+            assert_eq!(from_linear(to_linear(v)), v);
+        }
+        assert_eq!(to_linear(0), 0.0);
+        assert_eq!(to_linear(255), 1.0);
+    }
+
+    #[test]
+    fn test_blend_pixels_linear() {
+        let linear = Blending {
+            space: BlendSpace::Linear,
+            ..Blending::default()
+        };
+        let base = Rgba([0, 0, 0, 255]);
+        let overlay = Rgba([255, 255, 255, 128]);
+
+        // half of white over black is 128 in sRGB but about 188 when mixed in linear light
+        assert_eq!(
+            blend_pixels_with(base, overlay, Blending::default()),
+            Rgba([128, 128, 128, 255])
+        );
+        let mixed = blend_pixels_with(base, overlay, linear);
+        assert!((187..=189).contains(&mixed[0]), "{:?}", mixed);
+        assert_eq!(mixed[0], mixed[1]);
+
+        // the end points are unchanged
+        let color = Rgba([200, 30, 90, 255]);
+        assert_eq!(blend_pixels_with(color, Rgba([1, 2, 3, 0]), linear), color);
+        assert_eq!(
+            blend_pixels_with(Rgba([1, 2, 3, 255]), color, linear),
+            color
+        );
+    }
+
+    #[test]
+    fn test_blend_modes_separable() {
+        let base = Rgba([200, 100, 0, 255]);
+        let overlay = Rgba([128, 128, 128, 255]);
+        let blend = |mode| blend_pixels_with(base, overlay, srgb(mode));
+
+        assert_eq!(blend(BlendMode::Normal), Rgba([128, 128, 128, 255]));
+        assert_eq!(blend(BlendMode::Multiply), Rgba([100, 50, 0, 255]));
+        assert_eq!(blend(BlendMode::Screen), Rgba([228, 178, 128, 255]));
+        // overlay keeps dark and light base values on their side of the middle
+        assert_eq!(blend(BlendMode::Overlay), Rgba([200, 100, 0, 255]));
+        // soft light with a mid grey leaves the image almost unchanged
+        let soft = blend(BlendMode::SoftLight);
+        assert!((soft[0] as i32 - 200).abs() <= 1 && (soft[1] as i32 - 100).abs() <= 1);
+        assert_eq!(soft[2], 0);
+
+        // alpha still mixes the blended color with the base
+        let half = blend_pixels_with(base, Rgba([0, 0, 0, 128]), srgb(BlendMode::Multiply));
+        assert_eq!(half, Rgba([100, 50, 0, 255]));
+    }
+
+    #[test]
+    fn test_blend_modes_non_separable() {
+        let lum = |p: Rgba<u8>| 0.3 * p[0] as f32 + 0.59 * p[1] as f32 + 0.11 * p[2] as f32;
+        let base = Rgba([40, 160, 90, 255]);
+        let red = Rgba([255, 0, 0, 255]);
+
+        // color keeps the luminosity of the image with the hue of the overlay
+        let color = blend_pixels_with(base, red, srgb(BlendMode::Color));
+        assert!((lum(color) - lum(base)).abs() < 1.5);
+        assert!(color[0] > color[1] && color[1] == color[2]);
+
+        // luminosity keeps the hue of the image with the luminosity of the overlay
+        let luminosity = blend_pixels_with(base, red, srgb(BlendMode::Luminosity));
+        assert!((lum(luminosity) - lum(red)).abs() < 1.5);
+        assert!(luminosity[1] > luminosity[2] && luminosity[2] > luminosity[0]);
+
+        let grey = blend_pixels_with(base, Rgba([80, 80, 80, 255]), srgb(BlendMode::Color));
+        assert!(grey[0] == grey[1] && grey[1] == grey[2]);
+    }
+
+    const WHITE: Srgb<u8> = Srgb::new(255, 255, 255);
+
+    #[test]
+    fn test_blend_pixels_preserve_alpha() {
+        let blending = Blending {
+            preserve_alpha: true,
+            ..Blending::default()
+        };
+        let overlay = Rgba([255, 0, 0, 128]);
+
+        assert_eq!(
+            blend_pixels_with(Rgba([0, 0, 255, 255]), overlay, blending),
+            Rgba([128, 0, 127, 255])
+        );
+        // the overlay stays within the alpha of the source
+        assert_eq!(
+            blend_pixels_with(Rgba([0, 0, 255, 0]), overlay, blending)[3],
+            0
+        );
+        assert_eq!(
+            blend_pixels_with(Rgba([0, 0, 255, 100]), overlay, blending)[3],
+            100
+        );
+        assert_eq!(
+            blend_pixels_with(Rgba([0, 0, 255, 100]), overlay, Blending::default())[3],
+            255
+        );
+    }
+
+    #[test]
+    fn test_blend_over() {
+        // with an opaque base it is the same as blend_pixels
+        let base = Rgba([10, 200, 30, 255]);
+        let overlay = Rgba([250, 20, 90, 77]);
+        assert_eq!(blend_over(base, overlay), blend_pixels(base, overlay));
+
+        assert_eq!(
+            blend_over(Rgba([0, 0, 0, 0]), Rgba([255, 0, 0, 128])),
+            Rgba([255, 0, 0, 128])
+        );
+        assert_eq!(blend_over(Rgba([0, 0, 0, 0]), Rgba([0, 0, 0, 0]))[3], 0);
+        assert_eq!(
+            blend_over(Rgba([0, 0, 255, 128]), Rgba([255, 0, 0, 128])),
+            Rgba([170, 0, 85, 192])
+        );
+    }
+
+    #[test]
+    fn test_encode_image_jpeg_background() {
+        let img = dummy_image(8, 8, Rgba([0, 0, 0, 0]));
+        let jpeg = encode_image(
+            &img,
+            ImageFormat::Jpeg,
+            100,
+            Srgb::new(0, 128, 255),
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().into_rgb8();
+        let p = decoded.get_pixel(4, 4);
+        assert!(
+            p[0] < 5 && (p[1] as i32 - 128).abs() < 5 && p[2] > 250,
+            "{:?}",
+            p
+        );
+
+        let png = encode_image(
+            &img,
+            ImageFormat::Png,
+            80,
+            Srgb::new(0, 128, 255),
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgba8();
+        assert_eq!(*decoded.get_pixel(4, 4), Rgba([0, 0, 0, 0]));
+    }
+
+    fn options(gradient_variant: GradientColorType) -> OverlayOptions {
+        OverlayOptions {
+            gradient_variant,
+            fade: 1.0,
+            gradient: GradientSpec::default(),
+            resize: None,
+            blending: Blending::default(),
+            watermark: None,
+            text: Vec::new(),
+            animate: false,
+            preserve_metadata: false,
+        }
+    }
+
     fn dummy_image(width: u32, height: u32, color: Rgba<u8>) -> RgbaImage {
         let mut img = RgbaImage::new(width, height);
         for y in 0..height {
@@ -0,0 +1,266 @@
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Rules for which urls the service is allowed to fetch images from
+/// allow_hosts: when not empty only these hosts and their subdomains are fetched
+/// deny_hosts: these hosts and their subdomains are never fetched
+/// allow_private: also fetch from loopback, private and link-local addresses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FetchPolicy {
+    pub allow_hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+    pub allow_private: bool,
+}
+
+/// Why a url was refused by the fetch policy
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyViolation {
+    Scheme(String),
+    MissingHost,
+    HostNotAllowed(String),
+    HostDenied(String),
+    Address(String, IpAddr),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::Scheme(scheme) => write!(f, "scheme {} is not allowed", scheme),
+            PolicyViolation::MissingHost => write!(f, "url has no host"),
+            PolicyViolation::HostNotAllowed(host) => write!(f, "host {} is not allowed", host),
+            PolicyViolation::HostDenied(host) => write!(f, "host {} is denied", host),
+            PolicyViolation::Address(host, ip) => {
+                write!(f, "host {} resolves to the non public address {}", host, ip)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl FetchPolicy {
+    /// Check the scheme and host of the url, hosts given as ip addresses are checked directly
+    /// while names are checked by the resolver when connecting
+    pub fn check_url(&self, url: &Url) -> Result<(), PolicyViolation> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(PolicyViolation::Scheme(url.scheme().to_string()));
+        }
+        let host = url.host_str().ok_or(PolicyViolation::MissingHost)?;
+        if self.deny_hosts.iter().any(|h| host_matches(host, h)) {
+            return Err(PolicyViolation::HostDenied(host.to_string()));
+        }
+        if !self.allow_hosts.is_empty() && !self.allow_hosts.iter().any(|h| host_matches(host, h)) {
+            return Err(PolicyViolation::HostNotAllowed(host.to_string()));
+        }
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => self.check_addr(host, ip),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Refuse addresses that are not publicly routable unless private addresses are allowed
+    pub fn check_addr(&self, host: &str, ip: IpAddr) -> Result<(), PolicyViolation> {
+        if self.allow_private || is_public(ip) {
+            Ok(())
+        } else {
+            Err(PolicyViolation::Address(host.to_string(), ip))
+        }
+    }
+}
+
+/// The host equals the pattern or is a subdomain of it
+fn host_matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches('.');
+    host.eq_ignore_ascii_case(pattern)
+        || host.len().checked_sub(pattern.len() + 1).is_some_and(|i| {
+            host.as_bytes()[i] == b'.' && host[i + 1..].eq_ignore_ascii_case(pattern)
+        })
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this network" 0.0.0.0/8 and shared address space 100.64.0.0/10
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+/// The IPv4 address that an IPv6 address reaches, for the mapped ::ffff:a.b.c.d and compatible
+/// ::a.b.c.d forms, NAT64 64:ff9b::/96 and 6to4 2002::/16
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let v4 = |high: u16, low: u16| Some(Ipv4Addr::from(((high as u32) << 16) | low as u32));
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0 | 0xffff, high, low] => v4(high, low),
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => v4(high, low),
+        [0x2002, high, low, ..] => v4(high, low),
+        _ => None,
+    }
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [a, b, ..] = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // deprecated site-local fec0::/10 and documentation 2001:db8::/32
+        || a & 0xffc0 == 0xfec0
+        || (a == 0x2001 && b == 0x0db8))
+}
+
+/// Resolves host names for the http client and refuses the connection when any of the
+/// addresses is not allowed by the policy, so a public name can not point to an internal host
+pub struct PolicyResolver {
+    policy: Arc<FetchPolicy>,
+}
+
+impl PolicyResolver {
+    pub fn new(policy: Arc<FetchPolicy>) -> Self {
+        Self { policy }
+    }
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+            for addr in &addrs {
+                policy.check_addr(host, addr.ip())?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Find a policy violation in the source chain of an error from the http client
+pub fn find_violation(error: &(dyn std::error::Error + 'static)) -> Option<PolicyViolation> {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if let Some(violation) = e.downcast_ref::<PolicyViolation>() {
+            return Some(violation.clone());
+        }
+        current = e.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: &FetchPolicy, url: &str) -> Result<(), PolicyViolation> {
+        policy.check_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_check_url_scheme() {
+        let policy = FetchPolicy::default();
+        assert!(check(&policy, "https://example.com/a.png").is_ok());
+        assert!(check(&policy, "http://example.com/a.png").is_ok());
+        assert_eq!(
+            check(&policy, "file:///etc/passwd"),
+            Err(PolicyViolation::Scheme("file".into()))
+        );
+        assert_eq!(
+            check(&policy, "ftp://example.com/a.png"),
+            Err(PolicyViolation::Scheme("ftp".into()))
+        );
+    }
+
+    #[test]
+    fn test_check_url_host_lists() {
+        let policy = FetchPolicy {
+            allow_hosts: vec!["example.com".into()],
+            deny_hosts: vec!["internal.example.com".into()],
+            allow_private: false,
+        };
+        assert!(check(&policy, "https://example.com/a.png").is_ok());
+        assert!(check(&policy, "https://img.EXAMPLE.com/a.png").is_ok());
+        assert_eq!(
+            check(&policy, "https://badexample.com/a.png"),
+            Err(PolicyViolation::HostNotAllowed("badexample.com".into()))
+        );
+        assert_eq!(
+            check(&policy, "https://a.internal.example.com/a.png"),
+            Err(PolicyViolation::HostDenied("a.internal.example.com".into()))
+        );
+    }
+
+    #[test]
+    fn test_check_url_ip_literals() {
+        let policy = FetchPolicy::default();
+        for url in [
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fe80::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::127.0.0.1]/",
+            "http://[::10.0.0.1]/",
+            "http://[64:ff9b::10.0.0.1]/",
+            "http://[64:ff9b::169.254.169.254]/",
+            "http://[2002:c0a8:101::1]/",
+            "http://[2002:7f00:1::]/",
+            "http://[fec0::1]/",
+            "http://[2001:db8::1]/",
+        ] {
+            assert!(
+                matches!(check(&policy, url), Err(PolicyViolation::Address(..))),
+                "{}",
+                url
+            );
+        }
+        assert!(check(&policy, "http://93.184.216.34/").is_ok());
+        assert!(check(&policy, "http://[2606:4700::1111]/").is_ok());
+        assert!(check(&policy, "http://[64:ff9b::93.184.216.34]/").is_ok());
+        assert!(check(&policy, "http://[2002:5db8:d822::1]/").is_ok());
+
+        let policy = FetchPolicy {
+            allow_private: true,
+            ..FetchPolicy::default()
+        };
+        assert!(check(&policy, "http://127.0.0.1/").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resolver_refuses_loopback() {
+        let resolver = PolicyResolver::new(Arc::new(FetchPolicy::default()));
+        let err = resolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            find_violation(err.as_ref()),
+            Some(PolicyViolation::Address(..))
+        ));
+    }
+}
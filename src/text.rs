@@ -0,0 +1,415 @@
+use crate::overlay::blend_over;
+use ab_glyph::{Font, FontArc, GlyphId, PxScale, PxScaleFont, ScaleFont, point};
+use image::{Rgba, RgbaImage};
+use palette::Srgb;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const ELLIPSIS: char = '…';
+
+/// Horizontal alignment of the lines within the text box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/// Vertical placement of the lines within the text box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerticalAlign {
+    Top,
+    Center,
+    Bottom,
+}
+
+/// The area the text is laid out in, given as fractions (0.0 to 1.0) of the image size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// A text drawn on top of the overlay
+/// content: the text, a newline starts a new line
+/// font: name of a font in the font directory, the file name without the extension
+/// size: font size in pixels
+/// max_lines: lines after this are dropped and the last line ends with an ellipsis,
+/// the lines are also limited to what fits in the height of the box
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLayer {
+    pub content: String,
+    pub font: String,
+    pub size: f32,
+    pub color: Srgb<u8>,
+    pub align: TextAlign,
+    pub vertical_align: VerticalAlign,
+    pub area: TextBox,
+    pub max_lines: Option<u32>,
+}
+
+/// The fonts of a directory by file name without extension, .ttf and .otf files are loaded
+#[derive(Default)]
+pub struct FontLibrary {
+    fonts: HashMap<String, FontArc>,
+}
+
+impl FontLibrary {
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut fonts = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_font = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case("ttf") || e.eq_ignore_ascii_case("otf"));
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !is_font {
+                continue;
+            }
+            match FontArc::try_from_vec(fs::read(&path)?) {
+                Ok(font) => {
+                    fonts.insert(name.to_string(), font);
+                }
+                Err(e) => log::warn!("skipping font {}: {}", path.display(), e),
+            }
+        }
+        Ok(Self { fonts })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FontArc> {
+        self.fonts.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.fonts.len()
+    }
+}
+
+/// Width of the character when it follows previous on a line, including kerning
+fn advance(font: &PxScaleFont<&FontArc>, previous: Option<GlyphId>, c: char) -> (f32, GlyphId) {
+    let id = font.glyph_id(c);
+    let kern = previous.map_or(0.0, |previous| font.kern(previous, id));
+    (kern + font.h_advance(id), id)
+}
+
+/// Width in pixels of the text on a single line, including kerning
+fn text_width(font: &PxScaleFont<&FontArc>, text: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous: Option<GlyphId> = None;
+    for c in text.chars() {
+        let (w, id) = advance(font, previous, c);
+        width += w;
+        previous = Some(id);
+    }
+    width
+}
+
+/// Break the content into lines no wider than max_width, words that do not fit on a line of
+/// their own are broken between characters. With more than max_lines lines the rest is dropped
+/// and the last line is shortened to end with an ellipsis.
+/// The width of the line is kept while words are added and wrapping stops once there is a line
+/// more than max_lines, so long content costs no more than what can be shown
+fn wrap_lines(
+    font: &PxScaleFont<&FontArc>,
+    content: &str,
+    max_width: f32,
+    max_lines: usize,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    'paragraphs: for paragraph in content.lines() {
+        let mut line = String::new();
+        let mut width = 0.0;
+        let mut last: Option<GlyphId> = None;
+        for word in paragraph.split_whitespace() {
+            // the width the word adds to the line, with the space in front of it
+            let mut added = 0.0;
+            let mut previous = last;
+            let separator = (!line.is_empty()).then_some(' ');
+            for c in separator.into_iter().chain(word.chars()) {
+                let (w, id) = advance(font, previous, c);
+                added += w;
+                previous = Some(id);
+            }
+            if width + added <= max_width {
+                line.extend(separator);
+                line.push_str(word);
+                width += added;
+                last = previous;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            width = 0.0;
+            last = None;
+            for c in word.chars() {
+                if lines.len() > max_lines {
+                    break 'paragraphs;
+                }
+                let (mut w, mut id) = advance(font, last, c);
+                if !line.is_empty() && width + w > max_width {
+                    lines.push(std::mem::take(&mut line));
+                    (w, id) = advance(font, None, c);
+                    width = 0.0;
+                }
+                line.push(c);
+                width += w;
+                last = Some(id);
+            }
+        }
+        lines.push(line);
+        if lines.len() > max_lines {
+            break;
+        }
+    }
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            let (ellipsis, _) = advance(font, None, ELLIPSIS);
+            let mut width = 0.0;
+            let mut previous = None;
+            let mut end = 0;
+            for (i, c) in last.char_indices() {
+                let (w, id) = advance(font, previous, c);
+                width += w;
+                previous = Some(id);
+                if width + ellipsis > max_width {
+                    break;
+                }
+                end = i + c.len_utf8();
+            }
+            let mut shortened = last[..end].trim_end().to_string();
+            shortened.push(ELLIPSIS);
+            *last = shortened;
+        }
+    }
+    lines
+}
+
+/// Draw the text layer onto the image, glyph coverage is blended over the existing pixels
+pub fn draw_text(img: &mut RgbaImage, layer: &TextLayer, font: &FontArc) {
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let font = font.as_scaled(PxScale::from(layer.size));
+    let box_x = layer.area.x * width;
+    let box_y = layer.area.y * height;
+    let box_width = layer.area.width * width;
+    let box_height = layer.area.height * height;
+
+    let line_height = font.ascent() - font.descent() + font.line_gap();
+    let fitting = ((box_height + font.line_gap()) / line_height)
+        .floor()
+        .max(1.0) as usize;
+    let max_lines = layer
+        .max_lines
+        .map_or(fitting, |m| (m as usize).min(fitting));
+    let lines = wrap_lines(&font, &layer.content, box_width, max_lines);
+
+    let block_height = lines.len() as f32 * line_height - font.line_gap();
+    let top = box_y
+        + match layer.vertical_align {
+            VerticalAlign::Top => 0.0,
+            VerticalAlign::Center => (box_height - block_height) / 2.0,
+            VerticalAlign::Bottom => box_height - block_height,
+        };
+    for (i, line) in lines.iter().enumerate() {
+        let line_width = text_width(&font, line);
+        let mut x = box_x
+            + match layer.align {
+                TextAlign::Left => 0.0,
+                TextAlign::Center => (box_width - line_width) / 2.0,
+                TextAlign::Right => box_width - line_width,
+            };
+        let baseline = top + font.ascent() + i as f32 * line_height;
+        let mut previous: Option<GlyphId> = None;
+        for c in line.chars() {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                x += font.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(font.scale(), point(x, baseline));
+            if let Some(outlined) = font.outline_glyph(glyph) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|gx, gy, coverage| {
+                    let px = bounds.min.x as i64 + gx as i64;
+                    let py = bounds.min.y as i64 + gy as i64;
+                    if px >= 0 && py >= 0 && px < img.width() as i64 && py < img.height() as i64 {
+                        let pixel = img.get_pixel_mut(px as u32, py as u32);
+                        *pixel = blend_coverage(*pixel, layer.color, coverage);
+                    }
+                });
+            }
+            x += font.h_advance(id);
+            previous = Some(id);
+        }
+    }
+}
+
+/// Blend the color over the pixel with the glyph coverage as alpha
+fn blend_coverage(base: Rgba<u8>, color: Srgb<u8>, coverage: f32) -> Rgba<u8> {
+    let alpha = (coverage.clamp(0.0, 1.0) * 255.0).round() as u8;
+    blend_over(base, Rgba([color.red, color.green, color.blue, alpha]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_fonts() -> FontLibrary {
+        FontLibrary::load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fonts")).unwrap()
+    }
+
+    fn layer(content: &str) -> TextLayer {
+        TextLayer {
+            content: content.to_string(),
+            font: "DejaVuSansMono".to_string(),
+            size: 10.0,
+            color: Srgb::new(255, 255, 255),
+            align: TextAlign::Left,
+            vertical_align: VerticalAlign::Top,
+            area: TextBox {
+                x: 0.0,
+                y: 0.0,
+                width: 1.0,
+                height: 1.0,
+            },
+            max_lines: None,
+        }
+    }
+
+    #[test]
+    fn test_font_library_loads_fonts_by_name() {
+        let fonts = test_fonts();
+        assert_eq!(fonts.len(), 1);
+        assert!(fonts.get("DejaVuSansMono").is_some());
+        assert!(fonts.get("Missing").is_none());
+    }
+
+    #[test]
+    fn test_wrap_lines() {
+        let fonts = test_fonts();
+        let font = fonts
+            .get("DejaVuSansMono")
+            .unwrap()
+            .as_scaled(PxScale::from(10.0));
+        // the monospaced advance is about 6 pixels at this size, so 10 characters fit
+        let max_width = text_width(&font, "0123456789");
+
+        assert_eq!(
+            wrap_lines(&font, "the quick brown fox jumps", max_width, 10),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+        assert_eq!(
+            wrap_lines(&font, "abcdefghijklmno", max_width, 10),
+            vec!["abcdefghij", "klmno"]
+        );
+        assert_eq!(
+            wrap_lines(&font, "one\ntwo", max_width, 10),
+            vec!["one", "two"]
+        );
+        assert_eq!(
+            wrap_lines(&font, "the quick brown fox jumps", max_width, 2),
+            vec!["the quick", "brown fox…"]
+        );
+        let lines = wrap_lines(&font, "the quick brownish fox", max_width, 1);
+        assert_eq!(lines, vec!["the quick…"]);
+
+        // long content only wraps the lines that can be shown
+        let long = "word ".repeat(200_000);
+        let lines = wrap_lines(&font, &long, max_width, 3);
+        assert_eq!(lines, vec!["word word", "word word", "word word…"]);
+        let long_word = "x".repeat(200_000);
+        let lines = wrap_lines(&font, &long_word, max_width, 2);
+        assert_eq!(lines, vec!["xxxxxxxxxx", "xxxxxxxxx…"]);
+    }
+
+    #[test]
+    fn test_draw_text_alignment() {
+        let fonts = test_fonts();
+        let font = fonts.get("DejaVuSansMono").unwrap();
+        let columns = |img: &RgbaImage| -> Vec<u32> {
+            (0..img.width())
+                .filter(|&x| (0..img.height()).any(|y| img.get_pixel(x, y)[0] > 128))
+                .collect()
+        };
+
+        let mut left = RgbaImage::from_pixel(100, 20, Rgba([0, 0, 0, 255]));
+        draw_text(&mut left, &layer("II"), font);
+        let mut right = RgbaImage::from_pixel(100, 20, Rgba([0, 0, 0, 255]));
+        draw_text(
+            &mut right,
+            &TextLayer {
+                align: TextAlign::Right,
+                ..layer("II")
+            },
+            font,
+        );
+
+        let (left, right) = (columns(&left), columns(&right));
+        assert!(!left.is_empty());
+        assert!(*left.last().unwrap() < 20);
+        assert!(*right.first().unwrap() > 80);
+    }
+
+    #[test]
+    fn test_draw_text_stays_in_max_lines() {
+        let fonts = test_fonts();
+        let font = fonts.get("DejaVuSansMono").unwrap();
+        let mut img = RgbaImage::from_pixel(40, 100, Rgba([0, 0, 0, 255]));
+        draw_text(
+            &mut img,
+            &TextLayer {
+                max_lines: Some(1),
+                ..layer("one two three four five six")
+            },
+            font,
+        );
+
+        let rows: Vec<u32> = (0..img.height())
+            .filter(|&y| (0..img.width()).any(|x| img.get_pixel(x, y)[0] > 0))
+            .collect();
+        assert!(!rows.is_empty());
+        assert!(*rows.last().unwrap() < 15);
+    }
+
+    #[test]
+    fn test_blend_coverage() {
+        let base = Rgba([0, 0, 0, 255]);
+        let white = Srgb::new(255, 255, 255);
+        assert_eq!(blend_coverage(base, white, 0.0), base);
+        assert_eq!(blend_coverage(base, white, 1.0), Rgba([255, 255, 255, 255]));
+        assert_eq!(blend_coverage(base, white, 0.5), Rgba([128, 128, 128, 255]));
+
+        // on a transparent base the edges keep the text color instead of fading to black
+        let clear = Rgba([0, 0, 0, 0]);
+        assert_eq!(blend_coverage(clear, white, 0.0), clear);
+        assert_eq!(
+            blend_coverage(clear, white, 0.5),
+            Rgba([255, 255, 255, 128])
+        );
+    }
+
+    #[test]
+    fn test_draw_text_on_transparent_image() {
+        let fonts = test_fonts();
+        let mut img = RgbaImage::new(60, 20);
+        let mut text = layer("Hello");
+        text.color = Srgb::new(200, 100, 50);
+        draw_text(&mut img, &text, fonts.get("DejaVuSansMono").unwrap());
+
+        let drawn: Vec<&Rgba<u8>> = img.pixels().filter(|p| p[3] > 0).collect();
+        assert!(
+            drawn.iter().any(|p| p[3] < 255),
+            "expected antialiased edges"
+        );
+        for pixel in drawn {
+            assert_eq!(&pixel.0[..3], &[200, 100, 50], "{:?}", pixel);
+        }
+    }
+}